@@ -0,0 +1,96 @@
+//! What nodes say to each other over their TCP links.
+
+use std::net::SocketAddrV4;
+
+use bincode::{Decode, Encode};
+
+use crate::{
+    connect::{causal::VectorClock, history::Summary, transfer::FileOffer},
+    identity::PublicKey,
+};
+
+/// The channel every node starts in.
+pub const DEFAULT_CHANNEL: &str = "general";
+
+/// A single frame sent over a TCP link between two nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum Frame {
+    /// First frame sent by both ends of a link, to be answered in the [`Hello`](Frame::Hello).
+    Challenge {
+        nonce: [u8; 32],
+    },
+    /// Tells the other side the id this node goes by and which channels it's in and, if it's in a private room,
+    /// proves it knows the room's key by authenticating the other side's challenge with it.
+    Hello {
+        id: SocketAddrV4,
+        room_proof: Option<[u8; 32]>,
+        channels: Vec<String>,
+    },
+    /// The channels the sender is in, sent whenever it joins or parts one.
+    Subscriptions(Vec<String>),
+    Chat(ChatMessage),
+    /// A message meant only for the node at the other end of the link, to be answered with an [`Ack`](Frame::Ack).
+    Private(PrivateMessage),
+    /// Confirms that the [`PrivateMessage`] with this id was received.
+    Ack {
+        id: u64,
+    },
+    /// What the sender has of the recent history, sent once the link is up.
+    Summary(Summary),
+    /// Messages the other side's [`Summary`](Frame::Summary) showed it was missing, oldest first.
+    History(Vec<ChatMessage>),
+    /// Offers to send a file, to be answered with an [`Accept`](Frame::Accept) or a [`Decline`](Frame::Decline).
+    Offer(FileOffer),
+    /// Asks for the offered file, starting at `offset`.
+    Accept {
+        id: u64,
+        offset: u64,
+    },
+    Decline {
+        id: u64,
+    },
+    /// Part of an accepted file.
+    Chunk {
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// The whole file was received, and whether it matched its offer.
+    Received {
+        id: u64,
+        verified: bool,
+    },
+    /// Other nodes the sender is linked to, sent by seed nodes once a link is up so that the other side links to
+    /// them too. Only heeded from the peers the other side was configured to dial.
+    Peers(Vec<SocketAddrV4>),
+}
+
+/// Identifies a message across every node it's relayed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode)]
+pub struct MessageId {
+    /// Key of the node that wrote the message.
+    pub origin: PublicKey,
+    /// Increases with each message written by `origin`.
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    /// How many more times the message may be relayed.
+    pub ttl: u8,
+    /// What the author had seen of the channel when writing the message.
+    pub deps: VectorClock,
+    /// Only nodes in this channel receive the message.
+    pub channel: String,
+    pub author: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct PrivateMessage {
+    /// Chosen by the sender, unique among the private messages it sends.
+    pub id: u64,
+    pub author: String,
+    pub text: String,
+}
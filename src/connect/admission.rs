@@ -0,0 +1,233 @@
+//! Which nodes may hold a link to this one, and how many.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, anyhow};
+use thiserror::Error;
+
+/// A range of IPv4 addresses, like `10.0.0.0/8`. A single address is a range with a prefix of 32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(network: Ipv4Addr, prefix: u8) -> anyhow::Result<Self> {
+        if prefix > 32 {
+            return Err(anyhow!("prefix {prefix} is longer than 32 bits"));
+        }
+        Ok(Self { network, prefix })
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        (u32::from(ip) ^ u32::from(self.network)) & self.mask() == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (network, prefix) = s.split_once('/').unwrap_or((s, "32"));
+        Self::new(
+            network
+                .parse()
+                .with_context(|| format!("bad address in {s}"))?,
+            prefix
+                .parse()
+                .with_context(|| format!("bad prefix in {s}"))?,
+        )
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Limits on the links of a node, applied to the streams it accepts and to the peers it dials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdmissionPolicy {
+    /// Most streams open at once, links and handshakes included.
+    pub max_connections: usize,
+    /// Most streams open at once with a single IP, as several nodes may run on the same host.
+    pub per_ip: usize,
+    /// If not empty, only addresses in one of these ranges are linked with.
+    pub allow: Vec<Cidr>,
+    /// Addresses in these ranges are never linked with, even if allowed.
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            per_ip: 16,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl AdmissionPolicy {
+    /// Whether the allow and deny lists let `ip` in, whatever the number of streams.
+    pub fn permits(&self, ip: Ipv4Addr) -> Result<(), Rejection> {
+        if let Some(range) = self.deny.iter().find(|range| range.contains(ip)) {
+            return Err(Rejection::Denied(*range));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|range| range.contains(ip)) {
+            return Err(Rejection::NotAllowed);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("address is in the denied range {0}")]
+    Denied(Cidr),
+    #[error("address isn't in any allowed range")]
+    NotAllowed,
+    #[error("too many connections already")]
+    TooManyConnections,
+    #[error("too many connections with that address already")]
+    TooManyFromIp,
+}
+
+impl Rejection {
+    /// Whether it's the address itself that is refused, rather than one stream too many.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Rejection::Denied(_) | Rejection::NotAllowed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    policy: AdmissionPolicy,
+    by_ip: HashMap<Ipv4Addr, usize>,
+    total: usize,
+}
+
+/// Counts the streams open with each address, admitting new ones according to an [`AdmissionPolicy`].
+#[derive(Debug, Clone, Default)]
+pub struct Gate {
+    open: Arc<Mutex<Open>>,
+}
+
+/// A stream let in by a [`Gate`], counted until this is dropped.
+#[derive(Debug)]
+pub struct Ticket {
+    open: Arc<Mutex<Open>>,
+    ip: Ipv4Addr,
+}
+
+impl Gate {
+    /// Apply `policy` to the streams opened from now on.
+    pub fn set_policy(&self, policy: AdmissionPolicy) {
+        self.open.lock().unwrap().policy = policy;
+    }
+
+    pub fn permits(&self, ip: Ipv4Addr) -> Result<(), Rejection> {
+        self.open.lock().unwrap().policy.permits(ip)
+    }
+
+    /// Count a stream with `ip`, if the policy lets it in.
+    pub fn admit(&self, ip: Ipv4Addr) -> Result<Ticket, Rejection> {
+        let mut open = self.open.lock().unwrap();
+        open.policy.permits(ip)?;
+        if open.total >= open.policy.max_connections {
+            return Err(Rejection::TooManyConnections);
+        }
+        let per_ip = open.policy.per_ip;
+        let from_ip = open.by_ip.entry(ip).or_default();
+        if *from_ip >= per_ip {
+            return Err(Rejection::TooManyFromIp);
+        }
+        *from_ip += 1;
+        open.total += 1;
+
+        Ok(Ticket {
+            open: self.open.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(from_ip) = open.by_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                open.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(Ipv4Addr::new(192, 168, 1, 77)));
+        assert!(!lan.contains(Ipv4Addr::new(192, 168, 2, 1)));
+        let host: Cidr = "10.1.2.3".parse().unwrap();
+        assert_eq!(host.to_string(), "10.1.2.3/32");
+        assert!(
+            host.contains(Ipv4Addr::new(10, 1, 2, 3)) && !host.contains(Ipv4Addr::new(10, 1, 2, 4))
+        );
+        assert!(
+            "0.0.0.0/0"
+                .parse::<Cidr>()
+                .unwrap()
+                .contains(Ipv4Addr::BROADCAST)
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn admits_within_the_lists_and_limits() {
+        let gate = Gate::default();
+        gate.set_policy(AdmissionPolicy {
+            max_connections: 3,
+            per_ip: 2,
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.6.6.0/24".parse().unwrap()],
+        });
+        let (a, b) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+
+        assert_eq!(
+            gate.admit(Ipv4Addr::new(192, 168, 0, 1)).unwrap_err(),
+            Rejection::NotAllowed
+        );
+        assert!(matches!(
+            gate.admit(Ipv4Addr::new(10, 6, 6, 6)),
+            Err(Rejection::Denied(_))
+        ));
+        let first = gate.admit(a).unwrap();
+        let _second = gate.admit(a).unwrap();
+        assert_eq!(gate.admit(a).unwrap_err(), Rejection::TooManyFromIp);
+        let _third = gate.admit(b).unwrap();
+        assert_eq!(gate.admit(b).unwrap_err(), Rejection::TooManyConnections);
+
+        drop(first);
+        assert!(gate.admit(b).is_ok());
+    }
+}
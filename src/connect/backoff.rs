@@ -0,0 +1,72 @@
+//! Delays between attempts at reaching a peer.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponential backoff with jitter: the delay doubles after each failed attempt, up to a limit, and a random part
+/// of up to half of it is taken off so that nodes which lost each other at the same time don't retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// How many delays were handed out since the last success.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+        delay - delay.mul_f64(rand::thread_rng().r#gen::<f64>() / 2.)
+    }
+
+    /// Start over from the shortest delay, after a success.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(250), Duration::from_secs(30))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_the_limit_with_jitter() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        for limit in [1, 2, 4, 8, 10, 10] {
+            let limit = Duration::from_secs(limit);
+            let delay = backoff.next_delay();
+            assert!(
+                limit / 2 <= delay && delay <= limit,
+                "{delay:?} outside of {limit:?}"
+            );
+        }
+        assert_eq!(backoff.attempt(), 6);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,303 @@
+//! Presenting the messages of each channel in causal order, so that replies never show up before what they reply to.
+//!
+//! Every [`ChatMessage`] carries the [`VectorClock`] of what its author had delivered in that channel when writing
+//! it. A [`CausalBuffer`] holds received messages back until everything in their clock has been delivered, or until
+//! it's clear the missing messages aren't coming (e.g. for someone who joined the channel late).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+
+use crate::{
+    chat::{ChatMessage, MessageId},
+    connect::manager::PeerId,
+    identity::PublicKey,
+};
+
+/// How long a message waits for the ones it depends on before being delivered anyway.
+pub const MAX_HOLD_BACK: Duration = Duration::from_secs(2);
+
+/// How many messages may be held back at once. Past that, the oldest ones are delivered anyway.
+const MAX_HELD_BACK: usize = 1024;
+
+/// For each author, the sequence number of the latest of their messages delivered.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct VectorClock(pub BTreeMap<PublicKey, u64>);
+
+impl VectorClock {
+    pub fn get(&self, origin: &PublicKey) -> u64 {
+        self.0.get(origin).copied().unwrap_or(0)
+    }
+
+    pub fn observe(&mut self, id: MessageId) {
+        let seq = self.0.entry(id.origin).or_default();
+        *seq = (*seq).max(id.seq);
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (&origin, &seq) in &other.0 {
+            self.observe(MessageId { origin, seq });
+        }
+    }
+
+    /// Whether every message delivered according to `other` has been according to this clock too.
+    pub fn covers(&self, other: &VectorClock) -> bool {
+        other.0.iter().all(|(origin, &seq)| self.get(origin) >= seq)
+    }
+}
+
+#[derive(Debug)]
+struct HeldBack {
+    from: PeerId,
+    message: ChatMessage,
+    since: Instant,
+}
+
+/// Orders the messages of every channel a node is in.
+#[derive(Debug)]
+pub struct CausalBuffer {
+    clocks: HashMap<String, VectorClock>,
+    held_back: Vec<HeldBack>,
+    max_wait: Duration,
+}
+
+impl CausalBuffer {
+    /// A buffer holding messages back for at most `max_wait`.
+    pub fn new(max_wait: Duration) -> Self {
+        Self {
+            clocks: HashMap::new(),
+            held_back: Vec::new(),
+            max_wait,
+        }
+    }
+
+    /// The clock to stamp a new message `id` written to `channel` with. The message counts as delivered afterwards.
+    pub fn stamp(&mut self, channel: &str, id: MessageId) -> VectorClock {
+        let clock = self.clocks.entry(channel.to_owned()).or_default();
+        let deps = clock.clone();
+        clock.observe(id);
+
+        deps
+    }
+
+    /// Take in a `message` received from `from`, returning every message that can now be delivered, in order.
+    pub fn receive(
+        &mut self,
+        from: PeerId,
+        message: ChatMessage,
+        now: Instant,
+    ) -> Vec<(PeerId, ChatMessage)> {
+        self.held_back.push(HeldBack {
+            from,
+            message,
+            since: now,
+        });
+
+        self.release(now)
+    }
+
+    /// Take in `messages` from a peer's history, which are in an order they can be delivered in. Whatever they depend
+    /// on that's still missing is older than that history and given up on.
+    pub fn catch_up(
+        &mut self,
+        messages: Vec<(PeerId, ChatMessage)>,
+        now: Instant,
+    ) -> Vec<(PeerId, ChatMessage)> {
+        for (_, message) in &messages {
+            self.mark_delivered(message);
+        }
+        let mut delivered = messages;
+        delivered.extend(self.release(now));
+
+        delivered
+    }
+
+    /// Count `message` as delivered, giving up on whatever it depends on that's still missing.
+    pub fn mark_delivered(&mut self, message: &ChatMessage) {
+        let clock = self.clocks.entry(message.channel.clone()).or_default();
+        clock.merge(&message.deps);
+        clock.observe(message.id);
+    }
+
+    /// Return the held back messages whose dependencies have been delivered, along with those that have waited for
+    /// them for too long, in the order they should be delivered in.
+    pub fn release(&mut self, now: Instant) -> Vec<(PeerId, ChatMessage)> {
+        let mut delivered = Vec::new();
+        let no_clock = VectorClock::default();
+        loop {
+            let ready = self.held_back.iter().position(|held| {
+                self.clocks
+                    .get(&held.message.channel)
+                    .unwrap_or(&no_clock)
+                    .covers(&held.message.deps)
+            });
+            let next = ready
+                .or_else(|| {
+                    self.held_back
+                        .iter()
+                        .position(|held| now.duration_since(held.since) >= self.max_wait)
+                })
+                .or_else(|| (self.held_back.len() > MAX_HELD_BACK).then_some(0));
+            let Some(next) = next else {
+                break;
+            };
+
+            let HeldBack { from, message, .. } = self.held_back.remove(next);
+            self.mark_delivered(&message);
+            delivered.push((from, message));
+        }
+
+        delivered
+    }
+}
+
+impl Default for CausalBuffer {
+    fn default() -> Self {
+        Self::new(MAX_HOLD_BACK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+    use crate::{connect::gossip::DEFAULT_TTL, identity::Identity};
+
+    use super::*;
+
+    const CHANNEL: &str = "general";
+
+    /// A node writing messages and delivering those of others through its buffer.
+    struct Author {
+        id: PeerId,
+        key: PublicKey,
+        seq: u64,
+        buffer: CausalBuffer,
+        delivered: Vec<ChatMessage>,
+    }
+
+    impl Author {
+        fn new(port: u16) -> Self {
+            Self {
+                id: PeerId::new(Ipv4Addr::LOCALHOST, port),
+                key: Identity::generate().public_key(),
+                seq: 0,
+                buffer: CausalBuffer::default(),
+                delivered: Vec::new(),
+            }
+        }
+
+        fn write(&mut self, text: &str) -> ChatMessage {
+            self.seq += 1;
+            let id = MessageId {
+                origin: self.key,
+                seq: self.seq,
+            };
+            let message = ChatMessage {
+                id,
+                ttl: DEFAULT_TTL,
+                deps: self.buffer.stamp(CHANNEL, id),
+                channel: CHANNEL.to_owned(),
+                author: self.key.fingerprint(),
+                text: text.to_owned(),
+            };
+            self.delivered.push(message.clone());
+            message
+        }
+
+        fn receive(&mut self, from: PeerId, message: ChatMessage, now: Instant) {
+            let released = self.buffer.receive(from, message, now);
+            self.delivered
+                .extend(released.into_iter().map(|(_, message)| message));
+        }
+
+        fn texts(&self) -> Vec<&str> {
+            self.delivered.iter().map(|m| m.text.as_str()).collect()
+        }
+    }
+
+    #[test]
+    fn replies_wait_for_what_they_reply_to() {
+        let now = Instant::now();
+        let (mut alice, mut bob, mut carol) = (Author::new(1), Author::new(2), Author::new(3));
+
+        let question = alice.write("anyone up for lunch?");
+        bob.receive(alice.id, question.clone(), now);
+        let answer = bob.write("sure");
+
+        // Carol hears the answer first, but only sees it after the question.
+        carol.receive(bob.id, answer, now);
+        assert!(carol.delivered.is_empty());
+        carol.receive(alice.id, question, now);
+        assert_eq!(carol.texts(), ["anyone up for lunch?", "sure"]);
+    }
+
+    #[test]
+    fn gives_up_on_messages_that_never_come() {
+        let now = Instant::now();
+        let (mut alice, mut bob, mut carol) = (Author::new(1), Author::new(2), Author::new(3));
+
+        let lost = alice.write("lost");
+        bob.receive(alice.id, lost, now);
+        let reply = bob.write("reply to the lost one");
+        let follow_up = bob.write("follow-up");
+
+        carol.receive(bob.id, reply, now);
+        carol.receive(bob.id, follow_up, now);
+        assert!(carol.delivered.is_empty());
+        assert!(carol.buffer.release(now + MAX_HOLD_BACK / 2).is_empty());
+        let released = carol.buffer.release(now + MAX_HOLD_BACK);
+        let texts: Vec<_> = released.iter().map(|(_, m)| m.text.as_str()).collect();
+        assert_eq!(texts, ["reply to the lost one", "follow-up"]);
+    }
+
+    /// Three authors chat over an in-memory network delivering messages in random order, each sometimes replying
+    /// to what it sees. Every author must deliver each message after all those it depends on.
+    #[test]
+    fn random_reordering_keeps_causal_order() {
+        let now = Instant::now();
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut authors = [Author::new(1), Author::new(2), Author::new(3)];
+            let mut in_flight: Vec<(usize, PeerId, ChatMessage)> = Vec::new();
+            let mut written = 0;
+            let send =
+                |in_flight: &mut Vec<_>, from: usize, authors: &[Author], message: ChatMessage| {
+                    for to in (0..authors.len()).filter(|&to| to != from) {
+                        in_flight.push((to, authors[from].id, message.clone()));
+                    }
+                };
+
+            for from in 0..authors.len() {
+                let message = authors[from].write(&format!("hello from {from}"));
+                send(&mut in_flight, from, &authors, message);
+                written += 1;
+            }
+            while !in_flight.is_empty() {
+                in_flight.shuffle(&mut rng);
+                let (to, from, message) = in_flight.pop().unwrap();
+                let before = authors[to].delivered.len();
+                authors[to].receive(from, message, now);
+                if authors[to].delivered.len() > before && written < 30 && rng.gen_bool(0.5) {
+                    let reply = authors[to].write(&format!("reply {written}"));
+                    send(&mut in_flight, to, &authors, reply);
+                    written += 1;
+                }
+            }
+
+            for author in &authors {
+                assert_eq!(author.delivered.len(), written, "seed {seed}");
+                let mut clock = VectorClock::default();
+                for message in &author.delivered {
+                    assert!(clock.covers(&message.deps), "seed {seed}: {message:?}");
+                    clock.observe(message.id);
+                }
+            }
+        }
+    }
+}
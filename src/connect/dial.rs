@@ -0,0 +1,68 @@
+//! Reaching a node that may be reachable at several addresses.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::{net::TcpStream, task::JoinSet};
+use tracing::debug;
+
+/// How long an attempt gets before the next endpoint is tried alongside it.
+pub const STAGGER: Duration = Duration::from_millis(250);
+
+/// Connect to the first of `endpoints` to answer, trying them in order, Happy Eyeballs style: the next endpoint is
+/// tried as soon as the previous attempt fails, or after `stagger` if it's still pending, without giving up on it.
+pub async fn connect_any(endpoints: &[SocketAddr], stagger: Duration) -> io::Result<TcpStream> {
+    let mut endpoints = endpoints.iter().copied().peekable();
+    let mut attempts = JoinSet::new();
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "no endpoint to connect to");
+    let mut next = endpoints.next();
+    loop {
+        if let Some(endpoint) = next.take() {
+            debug!("Trying {endpoint}");
+            attempts.spawn(TcpStream::connect(endpoint));
+        }
+        if attempts.is_empty() {
+            return Err(error);
+        }
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => match attempt {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    error = e;
+                    next = endpoints.next();
+                }
+                Err(e) => {
+                    error = io::Error::other(e);
+                    next = endpoints.next();
+                }
+            },
+            () = tokio::time::sleep(stagger), if endpoints.peek().is_some() => next = endpoints.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_endpoints_that_answer() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = closed_port().await;
+
+        let stream = connect_any(&[closed, open], STAGGER).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+
+        assert!(connect_any(&[closed], STAGGER).await.is_err());
+        assert!(connect_any(&[], STAGGER).await.is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Finds other nodes through the multicast and connects to them.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::{
+    select,
+    sync::{mpsc::Receiver, oneshot, watch},
+};
+use tracing::{info, trace, warn};
+
+use crate::connect::{
+    manager::{ConnectionManager, FORGET_AFTER, MAX_DISCOVERED_PEERS, PeerId},
+    mdns::MdnsServer,
+    multicast::{
+        communicator::Communicator,
+        limit::Dedup,
+        message::{Message, MulticastMessage},
+        server::{MulticastServer, Received},
+    },
+};
+
+/// How long a message repeated from the same source is ignored for.
+pub const REPEAT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Least time between the announcements made for nodes joining.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How nodes find each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// Through a [`MulticastServer`] on the multicast group of the node.
+    #[default]
+    Multicast,
+    /// Through an [`MdnsServer`], for networks which drop other multicast groups.
+    Mdns,
+}
+
+/// Where [`discover_peers`] announces this node.
+pub trait Announcer: Send {
+    fn send(&mut self, msg: MulticastMessage) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl<C: Communicator> Announcer for MulticastServer<MulticastMessage, C> {
+    async fn send(&mut self, msg: MulticastMessage) -> Result<()> {
+        MulticastServer::send(self, msg).await
+    }
+}
+
+impl<C: Communicator> Announcer for MdnsServer<C> {
+    async fn send(&mut self, msg: MulticastMessage) -> Result<()> {
+        MdnsServer::send(self, msg).await
+    }
+}
+
+/// What this version of the node supports, announced along with its endpoints.
+pub const FEATURES: &[&str] = &["mux", "history", "files", "private"];
+
+/// React to the [`MulticastMessage`]s of other nodes, heard through the multicast or mDNS, until `shutdown` fires, at
+/// which point a [`CloseServer`](MulticastMessage::CloseServer) is announced. A server announced with a signature
+/// can only be closed, and its id only announced again, by a message signed with the same key.
+///
+/// To keep a single link per pair of nodes, only the one with the lower address dials the other, once it hears its
+/// [`NewServer`](MulticastMessage::NewServer), and dials it again whenever the link breaks, until it hears its
+/// [`CloseServer`](MulticastMessage::CloseServer). Nodes which [`Announce`](MulticastMessage::Announce) their
+/// endpoints are dialed at those, and by the address they know themselves by. Servers are announced again after
+/// someone joins, at most once per [`ANNOUNCE_INTERVAL`], so that newcomers hear about everyone. Nodes announcing
+/// themselves with a legacy `HI` are always dialed, as they won't.
+///
+/// The channels this node is in are announced along with its server and again whenever they change, and those of
+/// other nodes are recorded in the `manager`.
+///
+/// Any other message from the same source as the last one of its kind, and the same as it, is ignored for
+/// [`REPEAT_WINDOW`], so that a flood of them doesn't turn into a flood of connections. The sender a message claims
+/// is left out of it, as anyone can claim any. Who announced which ids is remembered for [`FORGET_AFTER`], for at
+/// most [`MAX_DISCOVERED_PEERS`] of them.
+#[tracing::instrument(name = "Discover Peers", skip_all, fields(me = %manager.me()))]
+pub async fn discover_peers(
+    mut server: impl Announcer,
+    mut messages: Receiver<Received<MulticastMessage>>,
+    manager: ConnectionManager,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<()> {
+    let me = manager.me();
+    // Who announced each server, so that only they can close it, and when.
+    let mut announced_by = HashMap::new();
+    // The id each node that announced one goes by, by the address its other messages are heard from, and when.
+    let mut ids = HashMap::new();
+    let mut recent = Dedup::new(REPEAT_WINDOW);
+    let mut channels = manager.watch_channels();
+    announce(&mut server, &manager, &mut channels).await;
+    // When this node was last announced, and whether someone joined since.
+    let mut announced = Instant::now();
+    let mut joined = false;
+
+    loop {
+        let Received {
+            message,
+            source,
+            signer,
+            legacy,
+            ..
+        } = select! {
+            received = messages.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            Ok(()) = channels.changed() => {
+                let announcement = channels_announcement(me, &mut channels);
+                if let Err(e) = server.send(announcement).await {
+                    warn!("Couldn't announce the channels this node is in: {e}");
+                }
+                continue;
+            }
+            () = tokio::time::sleep_until((announced + ANNOUNCE_INTERVAL).into()), if joined => {
+                announce(&mut server, &manager, &mut channels).await;
+                announced = Instant::now();
+                joined = false;
+                continue;
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down. Announcing server close.");
+                if let Err(e) = server.send(MulticastMessage::CloseServer { port: me.port() }).await {
+                    warn!("Couldn't announce server close: {e}");
+                }
+                break;
+            }
+        };
+
+        if message != MulticastMessage::Join
+            && recent.is_repeat((source, message.kind()), message.clone(), Instant::now())
+        {
+            trace!("Ignoring {message:?} repeated by {source}");
+            continue;
+        }
+        match message {
+            MulticastMessage::Join => joined = true,
+            MulticastMessage::NewServer { port } => {
+                let addr = resolve(&ids, source, port);
+                if let Some(signer) = signer {
+                    remember(&mut announced_by, addr, signer.key);
+                }
+                // Older nodes only wait to be dialed.
+                if addr == me || (addr < me && !legacy) || manager.is_connected(addr) {
+                    continue;
+                }
+                info!("Received NewServer from {addr}");
+                manager.connect_discovered(addr);
+            }
+            MulticastMessage::Announce {
+                id,
+                name,
+                endpoints,
+                features,
+            } => {
+                if let Some((key, at)) = announced_by.get(&id)
+                    && at.elapsed() < FORGET_AFTER
+                    && signer.as_ref().is_none_or(|signer| signer.key != *key)
+                {
+                    warn!(
+                        "Ignoring Announce of {id}, which wasn't signed by whoever announced it first."
+                    );
+                    continue;
+                }
+                let key = signer.map(|signer| signer.key);
+                if let Some(key) = key {
+                    remember(&mut announced_by, id, key);
+                }
+                remember(&mut ids, SocketAddrV4::new(*source.ip(), id.port()), id);
+                if id <= me || manager.is_connected(id) {
+                    continue;
+                }
+                info!("{name} ({id}) announced {endpoints:?}, supporting {features:?}");
+                manager.connect_via(id, key, endpoints);
+            }
+            MulticastMessage::CloseServer { port } => {
+                let addr = resolve(&ids, source, port);
+                if let Some((key, _)) = announced_by.get(&addr)
+                    && signer.is_none_or(|signer| signer.key != *key)
+                {
+                    warn!(
+                        "Ignoring CloseServer for {addr}, which wasn't signed by whoever announced it."
+                    );
+                    continue;
+                }
+                announced_by.remove(&addr);
+                ids.retain(|_, (id, _)| *id != addr);
+                recent.forget(|(from, _)| *from == source);
+                manager.disconnect(addr);
+                manager.forget_subscriptions(addr);
+            }
+            MulticastMessage::Channels { port, channels } => {
+                let addr = resolve(&ids, source, port);
+                if addr != me {
+                    manager.set_subscriptions(addr, channels);
+                }
+            }
+        }
+    }
+
+    anyhow::Ok(())
+}
+
+/// Announce this node's endpoints, its server and the channels it's in.
+///
+/// A message that can't be sent is skipped, for the next announcement to make up for it.
+async fn announce(
+    server: &mut impl Announcer,
+    manager: &ConnectionManager,
+    channels: &mut watch::Receiver<BTreeSet<String>>,
+) {
+    let announcements = [
+        MulticastMessage::Announce {
+            id: manager.me(),
+            name: manager.name().to_owned(),
+            endpoints: manager.endpoints(),
+            features: FEATURES.iter().map(ToString::to_string).collect(),
+        },
+        MulticastMessage::NewServer {
+            port: manager.me().port(),
+        },
+        channels_announcement(manager.me(), channels),
+    ];
+    for announcement in announcements {
+        if let Err(e) = server.send(announcement).await {
+            warn!("Couldn't announce this node: {e}");
+        }
+    }
+}
+
+/// The id of the node listening on `port` at the address of `source`.
+fn resolve(
+    ids: &HashMap<SocketAddrV4, (PeerId, Instant)>,
+    source: SocketAddrV4,
+    port: u16,
+) -> PeerId {
+    let addr = SocketAddrV4::new(*source.ip(), port);
+    ids.get(&addr).map_or(addr, |(id, _)| *id)
+}
+
+/// Record `value` under `key`, heard of just now, unless `map` is full of [`MAX_DISCOVERED_PEERS`] entries heard of
+/// in the last [`FORGET_AFTER`]: those are kept rather than the new one, so that a flood can't push them out.
+fn remember<K: Eq + Hash, V>(map: &mut HashMap<K, (V, Instant)>, key: K, value: V) {
+    if map.len() >= MAX_DISCOVERED_PEERS && !map.contains_key(&key) {
+        map.retain(|_, (_, at)| at.elapsed() < FORGET_AFTER);
+        if map.len() >= MAX_DISCOVERED_PEERS {
+            trace!("Already remembering {} nodes. Ignoring another.", map.len());
+            return;
+        }
+    }
+    map.insert(key, (value, Instant::now()));
+}
+
+fn channels_announcement(
+    me: PeerId,
+    channels: &mut watch::Receiver<BTreeSet<String>>,
+) -> MulticastMessage {
+    MulticastMessage::Channels {
+        port: me.port(),
+        channels: channels.borrow_and_update().iter().cloned().collect(),
+    }
+}
@@ -0,0 +1,61 @@
+//! Length-prefixed frames over byte streams: `[len: u32][payload]`, big endian.
+
+use std::io;
+
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest frame accepted by [`recv`], so that a misbehaving peer can't make us allocate arbitrary amounts of memory.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+pub(crate) async fn write_frame(
+    writer: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+) -> io::Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(io::Error::other)?;
+    writer
+        .write_all(&[&len.to_be_bytes(), bytes].concat())
+        .await
+}
+
+/// Read a frame into `buf`, returning its length.
+pub(crate) async fn read_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let len = reader.read_u32().await? as usize;
+    if len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Frame of {len} bytes doesn't fit in a {} bytes buffer",
+                buf.len()
+            ),
+        ));
+    }
+    reader.read_exact(&mut buf[..len]).await?;
+
+    Ok(len)
+}
+
+/// Encode `msg` and write it as a single frame.
+pub async fn send<T: Encode>(writer: &mut (impl AsyncWrite + Unpin), msg: &T) -> Result<()> {
+    let bytes = bincode::encode_to_vec(msg, bincode::config::standard())?;
+    write_frame(writer, &bytes).await?;
+
+    Ok(())
+}
+
+/// Read a single frame and decode it.
+pub async fn recv<T: Decode<()>>(reader: &mut (impl AsyncRead + Unpin)) -> Result<T> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("Frame of {len} bytes is too big"));
+    }
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await?;
+    let (msg, _) = bincode::decode_from_slice(&buf, bincode::config::standard())?;
+
+    Ok(msg)
+}
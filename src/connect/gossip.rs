@@ -0,0 +1,75 @@
+//! Relaying messages through nodes, so that they reach nodes the sender has no link to.
+//!
+//! Every message carries a [`MessageId`] and a hop budget. Nodes pass each message they haven't seen yet on to their
+//! other peers with one hop less, and drop the ones they have.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::chat::MessageId;
+
+/// How many hops a message may travel.
+pub const DEFAULT_TTL: u8 = 8;
+
+/// How many message ids a [`SeenCache`] remembers by default.
+pub const SEEN_CAPACITY: usize = 4096;
+
+/// The ids of the latest messages seen, forgetting the oldest ones past a capacity.
+#[derive(Debug)]
+pub struct SeenCache {
+    capacity: usize,
+    ids: HashSet<MessageId>,
+    order: VecDeque<MessageId>,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Remember `id`, returning whether it wasn't seen before.
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+
+        true
+    }
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        Self::new(SEEN_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::Identity;
+
+    use super::*;
+
+    #[test]
+    fn forgets_oldest_ids() {
+        let origin = Identity::generate().public_key();
+        let id = |seq| MessageId { origin, seq };
+        let mut seen = SeenCache::new(2);
+
+        assert!(seen.insert(id(1)));
+        assert!(!seen.insert(id(1)));
+        assert!(seen.insert(id(2)));
+        assert!(seen.insert(id(3)));
+        // 1 was pushed out by 3.
+        assert!(seen.insert(id(1)));
+        assert!(!seen.insert(id(3)));
+    }
+}
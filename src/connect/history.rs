@@ -0,0 +1,133 @@
+//! Catching up on what was said before a node came online.
+//!
+//! Every node keeps the latest messages of its channels in a [`History`]. When a link comes up, both ends send a
+//! [`Summary`] of theirs, and each answers with the messages the other is missing.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{chat::ChatMessage, connect::causal::VectorClock};
+
+/// How many messages a [`History`] keeps by default.
+pub const HISTORY_LEN: usize = 256;
+
+/// How many messages are sent per [`Frame::History`](crate::chat::Frame::History).
+pub const HISTORY_BATCH: usize = 32;
+
+/// For each channel, the latest message of each author in it.
+pub type Summary = BTreeMap<String, VectorClock>;
+
+/// The latest messages delivered, in the order they were delivered in.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    messages: VecDeque<ChatMessage>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record `message`, forgetting the oldest one if full.
+    pub fn push(&mut self, message: ChatMessage) {
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::new();
+        for message in &self.messages {
+            summary
+                .entry(message.channel.clone())
+                .or_default()
+                .observe(message.id);
+        }
+
+        summary
+    }
+
+    /// The messages in `channels` that are newer than what `summary` has of their authors, in order.
+    pub fn missing(&self, summary: &Summary, channels: impl Fn(&str) -> bool) -> Vec<ChatMessage> {
+        let none = VectorClock::default();
+        self.messages
+            .iter()
+            .filter(|message| {
+                channels(&message.channel)
+                    && message.id.seq
+                        > summary
+                            .get(&message.channel)
+                            .unwrap_or(&none)
+                            .get(&message.id.origin)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(HISTORY_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chat::MessageId,
+        identity::{Identity, PublicKey},
+    };
+
+    use super::*;
+
+    fn message(origin: PublicKey, seq: u64, channel: &str) -> ChatMessage {
+        ChatMessage {
+            id: MessageId { origin, seq },
+            ttl: 0,
+            deps: VectorClock::default(),
+            channel: channel.to_owned(),
+            author: origin.fingerprint(),
+            text: format!("{seq}"),
+        }
+    }
+
+    #[test]
+    fn sends_what_the_summary_lacks() {
+        let (alice, bob) = (
+            Identity::generate().public_key(),
+            Identity::generate().public_key(),
+        );
+        let mut history = History::new(3);
+        for m in [
+            message(alice, 1, "general"),
+            message(bob, 1, "general"),
+            message(alice, 2, "rust"),
+            message(alice, 3, "general"),
+        ] {
+            history.push(m);
+        }
+
+        // The first message was forgotten.
+        let summary = history.summary();
+        assert_eq!(summary["general"].get(&alice), 3);
+        assert_eq!(summary["general"].get(&bob), 1);
+        assert_eq!(summary["rust"].get(&alice), 2);
+
+        let mut theirs = Summary::new();
+        theirs
+            .entry("general".to_owned())
+            .or_default()
+            .observe(MessageId {
+                origin: bob,
+                seq: 1,
+            });
+        let missing = history.missing(&theirs, |channel| channel == "general");
+        assert_eq!(missing, [message(alice, 3, "general")]);
+        let missing = history.missing(&Summary::new(), |_| true);
+        assert_eq!(missing.len(), 3);
+    }
+}
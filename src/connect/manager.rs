@@ -0,0 +1,1441 @@
+//! Keeps track of the TCP links to every other node.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::{SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use thiserror::Error;
+use tokio::{
+    net::{TcpStream, tcp::OwnedReadHalf},
+    sync::{
+        Semaphore,
+        mpsc::{self, Receiver, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task::AbortHandle,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    chat::{ChatMessage, DEFAULT_CHANNEL, Frame, MessageId, PrivateMessage},
+    connect::{
+        admission::{AdmissionPolicy, Gate},
+        backoff::Backoff,
+        causal::CausalBuffer,
+        dial::{STAGGER, connect_any},
+        gossip::{DEFAULT_TTL, SeenCache},
+        history::{HISTORY_BATCH, HISTORY_LEN, History},
+        mux::{Mux, MuxFrame, QueuePolicy, QueueStats},
+        outbox::Outbox,
+        secure::{self, SecureReader},
+        transfer::{FileOffer, Incoming, Transfers, read_chunk},
+    },
+    identity::{Identity, KeyStore, PublicKey, TrustError},
+    room::RoomKey,
+    store::{MessageStore, StoredMessage},
+};
+
+/// Prefix of the bytes authenticated by a room proof, so that it can't be mistaken for any other MAC.
+const ROOM_PROOF_CONTEXT: &[u8] = b"chat-async room proof";
+
+/// How often [`ConnectionManager::release_held_back`] looks for messages that have waited too long.
+const HOLD_BACK_CHECK: Duration = Duration::from_millis(250);
+
+/// How many peers may be dialed at once, so that a flood of announcements doesn't open as many connections.
+const MAX_DIALS: usize = 16;
+
+/// How long dialing a peer may take before giving up on that attempt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a new link may take to get through the handshake and hellos before it's dropped, so that streams which
+/// stall don't hold on to their admission.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a discovered peer is dialed for, since it was last heard of, before giving up on it.
+pub const FORGET_AFTER: Duration = Duration::from_secs(600);
+
+/// How many peers heard of through discovery are dialed at once, and remembered by discovery.
+pub const MAX_DISCOVERED_PEERS: usize = 256;
+
+/// How many peers a seed shares, or are taken from a [`Frame::Peers`], or are dialed at all for having been shared.
+const MAX_SHARED_PEERS: usize = 64;
+
+/// How many attempts in a row to link to a shared peer may fail before giving up on it.
+const MAX_SHARED_ATTEMPTS: u32 = 5;
+
+/// Longest channel name, and most channels a node may be in, so that they fit in an announcement.
+pub const MAX_CHANNEL_LEN: usize = 64;
+pub const MAX_CHANNELS: usize = 32;
+
+/// Identifies a node by the address its [`TcpListener`](tokio::net::TcpListener) can be reached at.
+pub type PeerId = SocketAddrV4;
+
+/// Something that happened to the links managed by a [`ConnectionManager`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    PeerConnected {
+        peer: PeerId,
+        name: String,
+        key: PublicKey,
+    },
+    PeerDisconnected {
+        peer: PeerId,
+    },
+    /// A message in one of the channels this node is in, received from `from` for the first time. It was written
+    /// by whoever its [`origin`](MessageId::origin) is, and `from` may have only relayed it.
+    ///
+    /// Messages are delivered in causal order: after the ones they depend on, unless those took too long to arrive.
+    Message {
+        from: PeerId,
+        message: ChatMessage,
+    },
+    /// A message sent only to this node by the peer that authenticated as `name` with `key`, which is also the
+    /// message's [`author`](PrivateMessage::author).
+    PrivateMessage {
+        from: PeerId,
+        name: String,
+        key: PublicKey,
+        message: PrivateMessage,
+    },
+    /// `peer` received the private message with this id, which is no longer in the outbox.
+    Delivered {
+        peer: PeerId,
+        id: u64,
+    },
+    /// `from` offers a file, to be [accepted](ConnectionManager::accept_file) or
+    /// [declined](ConnectionManager::decline_file).
+    FileOffered {
+        from: PeerId,
+        offer: FileOffer,
+    },
+    /// `peer` declined the file offered with this id.
+    FileDeclined {
+        peer: PeerId,
+        id: u64,
+    },
+    /// `done` bytes out of `size` of the file with this id went to, or came from, `peer`.
+    TransferProgress {
+        peer: PeerId,
+        id: u64,
+        done: u64,
+        size: u64,
+    },
+    /// The whole file with this id went to, or came from, `peer`, and whether it matched its offer.
+    TransferFinished {
+        peer: PeerId,
+        id: u64,
+        verified: bool,
+    },
+    /// The link to `peer` went through another [`LinkState`].
+    Link {
+        peer: PeerId,
+        state: LinkState,
+    },
+}
+
+/// Where a link stands. Links this node [dialed](ConnectionManager::connect) go from [`Connecting`](Self::Connecting)
+/// to [`Up`](Self::Up), or to [`BackingOff`](Self::BackingOff) and then back to connecting, until they're up or
+/// given up on; and again once they break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connecting,
+    Up,
+    /// The last `attempt` failed, and the next one is in `delay`.
+    BackingOff {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// The peer won't be dialed again until it's heard of again.
+    GaveUp,
+}
+
+/// For how long a peer is dialed again and again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Want {
+    /// Until it's [disconnected](ConnectionManager::disconnect).
+    Always,
+    /// Until it wasn't heard of for [`FORGET_AFTER`] since the time given.
+    HeardAt(tokio::time::Instant),
+    /// Until [`MAX_SHARED_ATTEMPTS`] in a row failed, as it was only shared by a seed.
+    Shared,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SendError {
+    #[error("{0} was never seen, so there's no knowing who they are")]
+    Unknown(String),
+    #[error("{0} isn't connected")]
+    NotConnected(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum JoinError {
+    #[error("Channel names are at most {MAX_CHANNEL_LEN} bytes long")]
+    TooLong,
+    #[error("Already in {MAX_CHANNELS} channels")]
+    TooMany,
+}
+
+/// A private message on its way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outgoing {
+    /// The key of the recipient.
+    pub to: PublicKey,
+    /// Reported in the [`Event::Delivered`] once the recipient has the message.
+    pub id: u64,
+    /// Whether the recipient is offline, so that the message waits in the outbox until it's back.
+    pub queued: bool,
+}
+
+#[derive(Debug)]
+struct Peer {
+    /// Distinguishes this link from older ones to the same peer, so that a dying link doesn't remove its successor.
+    link: u64,
+    /// Whether this end opened the link.
+    dialed: bool,
+    name: String,
+    key: PublicKey,
+    outbound: Mux,
+    reader: AbortHandle,
+}
+
+/// Owns every TCP link, keeping at most one per peer.
+#[derive(Debug, Clone)]
+pub struct ConnectionManager {
+    me: PeerId,
+    name: String,
+    identity: Identity,
+    keys: KeyStore,
+    room: Option<RoomKey>,
+    peers: Arc<Mutex<HashMap<PeerId, Peer>>>,
+    /// The channels this node is in.
+    channels: Arc<watch::Sender<BTreeSet<String>>>,
+    /// The channels other nodes are in, as last told over their link or the multicast.
+    subscriptions: Arc<Mutex<HashMap<PeerId, BTreeSet<String>>>>,
+    /// The peers to keep a link to, by dialing them again whenever the link breaks.
+    wanted: Arc<Mutex<HashMap<PeerId, Want>>>,
+    /// Where each peer said it may be reached, for the peers that announced more than their id.
+    reachable_at: Arc<Mutex<HashMap<PeerId, Vec<SocketAddr>>>>,
+    /// The key each peer that announced where it may be reached signed the announcement with.
+    announced_keys: Arc<Mutex<HashMap<PeerId, PublicKey>>>,
+    /// Where this node says it may be reached, besides [`me`](Self::me).
+    advertised: Arc<Mutex<Vec<SocketAddr>>>,
+    /// The peers being dialed, or kept linked to, by a task of their own.
+    dialing: Arc<Mutex<HashSet<PeerId>>>,
+    /// Whether to tell every new peer about the others. See [`set_seed`](Self::set_seed).
+    seed: Arc<AtomicBool>,
+    /// Allows [`MAX_DIALS`] connection attempts at once.
+    dials: Arc<Semaphore>,
+    admission: Gate,
+    next_link: Arc<AtomicU64>,
+    /// What to do when a peer doesn't read its frames as fast as they're sent.
+    queue_policy: Arc<Mutex<QueuePolicy>>,
+    /// Id of the next private message and sequence number of the next message published. Both start at the time
+    /// the manager was created, in milliseconds, so that a restarted node doesn't reuse the ids of its earlier
+    /// messages.
+    next_private: Arc<AtomicU64>,
+    next_seq: Arc<AtomicU64>,
+    outbox: Arc<Mutex<Outbox>>,
+    next_transfer: Arc<AtomicU64>,
+    transfers: Arc<Mutex<Transfers>>,
+    /// The private messages received, by the key of their sender, so that those sent again aren't shown twice.
+    seen_private: Arc<Mutex<SeenCache>>,
+    seen: Arc<Mutex<SeenCache>>,
+    causal: Arc<Mutex<CausalBuffer>>,
+    history: Arc<Mutex<History>>,
+    store: Arc<Mutex<Option<MessageStore>>>,
+    events: UnboundedSender<Event>,
+}
+
+impl ConnectionManager {
+    /// Create a manager for the node reachable at `me`, which authenticates its links as `name` with `identity`
+    /// and pins the keys of its peers in `keys`. If a `room` is given, only links to nodes in it are kept.
+    ///
+    /// Everything that happens to its links is sent as an [`Event`].
+    pub fn new(
+        me: PeerId,
+        name: String,
+        identity: Identity,
+        keys: KeyStore,
+        room: Option<RoomKey>,
+    ) -> (Self, UnboundedReceiver<Event>) {
+        let (events, rx) = mpsc::unbounded_channel();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let manager = Self {
+            me,
+            name,
+            identity,
+            keys,
+            room,
+            peers: Default::default(),
+            channels: Arc::new(watch::Sender::new(BTreeSet::from([
+                DEFAULT_CHANNEL.to_owned()
+            ]))),
+            subscriptions: Default::default(),
+            wanted: Default::default(),
+            reachable_at: Default::default(),
+            announced_keys: Default::default(),
+            advertised: Default::default(),
+            dialing: Default::default(),
+            seed: Default::default(),
+            dials: Arc::new(Semaphore::new(MAX_DIALS)),
+            admission: Gate::default(),
+            next_link: Default::default(),
+            queue_policy: Default::default(),
+            next_private: Arc::new(AtomicU64::new(now)),
+            next_seq: Arc::new(AtomicU64::new(now)),
+            outbox: Default::default(),
+            next_transfer: Default::default(),
+            transfers: Default::default(),
+            seen_private: Default::default(),
+            seen: Default::default(),
+            causal: Default::default(),
+            history: Default::default(),
+            store: Default::default(),
+            events,
+        };
+
+        (manager, rx)
+    }
+
+    pub fn me(&self) -> PeerId {
+        self.me
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The peers there is currently a link to.
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.peers.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn is_connected(&self, peer: PeerId) -> bool {
+        self.peers.lock().unwrap().contains_key(&peer)
+    }
+
+    /// The channels this node is in.
+    pub fn channels(&self) -> BTreeSet<String> {
+        self.channels.borrow().clone()
+    }
+
+    /// Watch the channels this node is in, to announce them whenever they change.
+    pub fn watch_channels(&self) -> watch::Receiver<BTreeSet<String>> {
+        self.channels.subscribe()
+    }
+
+    /// Join `channel`, telling every peer. Returns whether this node wasn't in it already.
+    pub async fn join(&self, channel: &str) -> Result<bool, JoinError> {
+        if channel.len() > MAX_CHANNEL_LEN {
+            return Err(JoinError::TooLong);
+        }
+        let mut full = false;
+        let joined = self.channels.send_if_modified(|channels| {
+            full = channels.len() >= MAX_CHANNELS && !channels.contains(channel);
+            !full && channels.insert(channel.to_owned())
+        });
+        if full {
+            return Err(JoinError::TooMany);
+        }
+        if joined {
+            self.broadcast(Frame::Subscriptions(self.channels().into_iter().collect()))
+                .await;
+        }
+
+        Ok(joined)
+    }
+
+    /// Leave `channel`, telling every peer. Returns whether this node was in it.
+    pub async fn part(&self, channel: &str) -> bool {
+        let parted = self
+            .channels
+            .send_if_modified(|channels| channels.remove(channel));
+        if parted {
+            self.broadcast(Frame::Subscriptions(self.channels().into_iter().collect()))
+                .await;
+        }
+
+        parted
+    }
+
+    /// Record the channels `peer` announced being in.
+    pub fn set_subscriptions(&self, peer: PeerId, channels: impl IntoIterator<Item = String>) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(peer, channels.into_iter().collect());
+    }
+
+    /// Forget the channels of a `peer` that left.
+    pub fn forget_subscriptions(&self, peer: PeerId) {
+        self.subscriptions.lock().unwrap().remove(&peer);
+    }
+
+    /// Every channel known to have someone in it, this node included, and who is in it.
+    pub fn network_channels(&self) -> BTreeMap<String, Vec<PeerId>> {
+        let mut network: BTreeMap<String, Vec<PeerId>> = BTreeMap::new();
+        for channel in self.channels() {
+            network.entry(channel).or_default().push(self.me);
+        }
+        for (peer, channels) in self.subscriptions.lock().unwrap().iter() {
+            for channel in channels {
+                network.entry(channel.clone()).or_default().push(*peer);
+            }
+        }
+        for members in network.values_mut() {
+            members.sort();
+        }
+
+        network
+    }
+
+    /// Take ownership of every stream accepted from other nodes received from `rx`, until it is closed.
+    #[tracing::instrument(name = "Manage TCP Streams", skip_all, fields(me = %self.me))]
+    pub async fn manage(self, mut rx: Receiver<TcpStream>) -> Result<()> {
+        while let Some(stream) = rx.recv().await {
+            let ticket = match stream.peer_addr() {
+                Ok(SocketAddr::V4(addr)) => match self.admission.admit(*addr.ip()) {
+                    Ok(ticket) => ticket,
+                    Err(e) => {
+                        warn!("Rejected connection from {addr}: {e}");
+                        continue;
+                    }
+                },
+                Ok(addr) => {
+                    warn!("Rejected connection from {addr}: IPV6 not implemented.");
+                    continue;
+                }
+                Err(e) => {
+                    info!("Dropping connection that's already gone: {e}");
+                    continue;
+                }
+            };
+            let manager = self.clone();
+            tokio::spawn(async move {
+                let _ticket = ticket;
+                if let Err(e) = manager.add_stream(stream, None).await {
+                    log_link_error(&e);
+                }
+            });
+        }
+
+        anyhow::Ok(())
+    }
+
+    /// Open a link to `peer` in the background, unless there already is one (or one is being opened), and open it
+    /// again whenever it breaks, waiting longer and longer between failed attempts, until [`disconnect`] is called.
+    ///
+    /// [`disconnect`]: Self::disconnect
+    pub fn connect(&self, peer: PeerId) {
+        self.want(peer, Want::Always);
+    }
+
+    /// Like [`connect`](Self::connect), for a peer heard of through discovery just now: it's given up on once it
+    /// wasn't heard of again for [`FORGET_AFTER`].
+    ///
+    /// Past [`MAX_DISCOVERED_PEERS`] of them, newly discovered peers are ignored until some are given up on.
+    pub fn connect_discovered(&self, peer: PeerId) {
+        if self.has_room_for_discovered(peer) {
+            self.want(peer, Want::HeardAt(tokio::time::Instant::now()));
+        }
+    }
+
+    fn has_room_for_discovered(&self, peer: PeerId) -> bool {
+        let wanted = self.wanted.lock().unwrap();
+        if wanted.contains_key(&peer) {
+            return true;
+        }
+        let discovered = wanted
+            .values()
+            .filter(|want| matches!(want, Want::HeardAt(_)))
+            .count();
+        if discovered >= MAX_DISCOVERED_PEERS {
+            debug!("Already dialing {discovered} discovered peers. Ignoring {peer}.");
+            return false;
+        }
+
+        true
+    }
+
+    fn want(&self, peer: PeerId, want: Want) {
+        if let Err(e) = self.admission.permits(*peer.ip()) {
+            warn!("Not connecting to {peer}: {e}");
+            return;
+        }
+        self.wanted
+            .lock()
+            .unwrap()
+            .entry(peer)
+            .and_modify(|wanted| {
+                if *wanted != Want::Always && want != Want::Shared {
+                    *wanted = want;
+                }
+            })
+            .or_insert(want);
+        if self.is_connected(peer) || !self.dialing.lock().unwrap().insert(peer) {
+            return;
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            manager.keep_linked(peer).await;
+            manager.dialing.lock().unwrap().remove(&peer);
+        });
+    }
+
+    /// Like [`connect_discovered`](Self::connect_discovered), dialing `peer` at `endpoints`, the preferred first,
+    /// rather than at its id. Those that aren't permitted, or aren't IPv4, are skipped; the id is tried last.
+    ///
+    /// Whoever answers at those must go by `peer`, and have signed the announcement with `key` if it was signed, for
+    /// the link to be kept.
+    pub fn connect_via(
+        &self,
+        peer: PeerId,
+        key: Option<PublicKey>,
+        mut endpoints: Vec<SocketAddr>,
+    ) {
+        if !self.has_room_for_discovered(peer) {
+            return;
+        }
+        endpoints.retain(SocketAddr::is_ipv4);
+        self.reachable_at.lock().unwrap().insert(peer, endpoints);
+        match key {
+            Some(key) => self.announced_keys.lock().unwrap().insert(peer, key),
+            None => self.announced_keys.lock().unwrap().remove(&peer),
+        };
+        self.connect_discovered(peer);
+    }
+
+    /// Where to dial `peer`, the preferred first.
+    fn endpoints_of(&self, peer: PeerId) -> Vec<SocketAddr> {
+        let announced = self.reachable_at.lock().unwrap().get(&peer).cloned();
+        let mut endpoints: Vec<SocketAddr> = announced
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|endpoint| match endpoint {
+                SocketAddr::V4(addr) => self.admission.permits(*addr.ip()).is_ok(),
+                SocketAddr::V6(_) => false,
+            })
+            .collect();
+        if !endpoints.contains(&peer.into()) {
+            endpoints.push(peer.into());
+        }
+        endpoints
+    }
+
+    /// Where this node may be reached: the endpoints set with [`set_advertised`](Self::set_advertised), then
+    /// [`me`](Self::me).
+    pub fn endpoints(&self) -> Vec<SocketAddr> {
+        let mut endpoints = self.advertised.lock().unwrap().clone();
+        if !endpoints.contains(&self.me.into()) {
+            endpoints.push(self.me.into());
+        }
+        endpoints
+    }
+
+    /// Advertise that this node may also be reached at `endpoints`, the preferred first. Links are only ever made
+    /// over IPv4 for now, so IPv6 endpoints are left out.
+    pub fn set_advertised(&self, mut endpoints: Vec<SocketAddr>) {
+        endpoints.retain(SocketAddr::is_ipv4);
+        *self.advertised.lock().unwrap() = endpoints;
+    }
+
+    fn wants(&self, peer: PeerId) -> bool {
+        self.wanted.lock().unwrap().contains_key(&peer)
+    }
+
+    /// Stop dialing `peer` if it wasn't heard of for too long, or was only shared and `failed` attempts in a row,
+    /// returning whether it was given up on.
+    fn give_up(&self, peer: PeerId, failed: u32) -> bool {
+        let mut wanted = self.wanted.lock().unwrap();
+        let expired = match wanted.get(&peer) {
+            Some(Want::HeardAt(at)) => at.elapsed() >= FORGET_AFTER,
+            Some(Want::Shared) => failed >= MAX_SHARED_ATTEMPTS,
+            Some(Want::Always) | None => false,
+        };
+        if !expired {
+            return false;
+        }
+        wanted.remove(&peer);
+        self.reachable_at.lock().unwrap().remove(&peer);
+        self.announced_keys.lock().unwrap().remove(&peer);
+
+        true
+    }
+
+    async fn keep_linked(&self, peer: PeerId) {
+        let mut backoff = Backoff::default();
+        while self.wants(peer) && !self.is_connected(peer) {
+            match self.admission.admit(*peer.ip()) {
+                Ok(_ticket) => {
+                    let stream = {
+                        let _dial = self.dials.acquire().await.expect("never closed");
+                        self.link_state(peer, LinkState::Connecting);
+                        let endpoints = self.endpoints_of(peer);
+                        tokio::time::timeout(CONNECT_TIMEOUT, connect_any(&endpoints, STAGGER))
+                            .await
+                    };
+                    match stream {
+                        // Only returns once the link broke.
+                        Ok(Ok(stream)) => match self.clone().add_stream(stream, Some(peer)).await {
+                            Ok(true) => {
+                                backoff.reset();
+                                if let Some(Want::HeardAt(at)) =
+                                    self.wanted.lock().unwrap().get_mut(&peer)
+                                {
+                                    *at = tokio::time::Instant::now();
+                                }
+                            }
+                            Ok(false) => {}
+                            Err(e) => log_link_error(&e),
+                        },
+                        Ok(Err(e)) => info!("Error connecting to {peer}: {e}"),
+                        Err(_) => info!("Timed out connecting to {peer}"),
+                    }
+                }
+                Err(e) if e.is_permanent() => {
+                    warn!("Not connecting to {peer}: {e}");
+                    break;
+                }
+                Err(e) => warn!("Not connecting to {peer} for now: {e}"),
+            }
+            if !self.wants(peer) || self.is_connected(peer) {
+                break;
+            }
+            if self.give_up(peer, backoff.attempt() + 1) {
+                info!("Giving up on {peer}");
+                self.link_state(peer, LinkState::GaveUp);
+                break;
+            }
+            let delay = backoff.next_delay();
+            let attempt = backoff.attempt();
+            info!("Trying {peer} again in {delay:?}");
+            self.link_state(peer, LinkState::BackingOff { attempt, delay });
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn link_state(&self, peer: PeerId, state: LinkState) {
+        let _ = self.events.send(Event::Link { peer, state });
+    }
+
+    /// Send `frame` to every peer.
+    pub async fn broadcast(&self, frame: Frame) {
+        self.send_to_others(frame, None).await;
+    }
+
+    /// Send `frame` to every peer but `except`.
+    async fn send_to_others(&self, frame: Frame, except: Option<PeerId>) {
+        let outbound: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .map(|(_, peer)| peer.outbound.clone())
+            .collect();
+        for outbound in outbound {
+            let _ = outbound.send(frame.clone()).await;
+        }
+    }
+
+    /// Write `text` to `channel`, sending it to every peer, who relay it on to theirs. Only the nodes in the channel
+    /// deliver it.
+    pub async fn publish(&self, channel: &str, text: String) -> MessageId {
+        let id = MessageId {
+            origin: self.identity.public_key(),
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+        };
+        self.seen.lock().unwrap().insert(id);
+        let message = ChatMessage {
+            id,
+            ttl: DEFAULT_TTL,
+            deps: self.causal.lock().unwrap().stamp(channel, id),
+            channel: channel.to_owned(),
+            author: self.name.clone(),
+            text,
+        };
+        self.remember(&message);
+        self.broadcast(Frame::Chat(message)).await;
+
+        id
+    }
+
+    /// Deliver the messages held back for too long waiting for the ones they depend on, until the manager is
+    /// dropped.
+    pub async fn release_held_back(self) -> Result<()> {
+        let mut interval = tokio::time::interval(HOLD_BACK_CHECK);
+        loop {
+            interval.tick().await;
+            self.deliver(self.causal.lock().unwrap().release(Instant::now()));
+        }
+    }
+
+    /// Hand messages released by the [`CausalBuffer`] over as [`Event`]s, remembering them for peers that come
+    /// online later.
+    fn deliver(&self, released: Vec<(PeerId, ChatMessage)>) {
+        for (from, message) in released {
+            self.remember(&message);
+            let _ = self.events.send(Event::Message { from, message });
+        }
+    }
+
+    /// Keep `message` in the history, and in the store if there is one.
+    fn remember(&self, message: &ChatMessage) {
+        self.history.lock().unwrap().push(message.clone());
+        if let Some(store) = self.store.lock().unwrap().as_mut()
+            && let Err(e) = store.append(message)
+        {
+            warn!("Couldn't store message: {e}");
+        }
+    }
+
+    /// Keep every message from now on in `store`, and pick up where the last node using it left off: with its
+    /// latest messages as history, and without reusing its message ids.
+    pub fn attach_store(&self, store: MessageStore) -> Result<()> {
+        let me = self.identity.public_key();
+        for StoredMessage { message, .. } in store.recent(HISTORY_LEN)? {
+            self.seen.lock().unwrap().insert(message.id);
+            self.causal.lock().unwrap().mark_delivered(&message);
+            if message.id.origin == me {
+                self.next_seq
+                    .fetch_max(message.id.seq + 1, Ordering::Relaxed);
+            }
+            self.history.lock().unwrap().push(message);
+        }
+        *self.store.lock().unwrap() = Some(store);
+
+        Ok(())
+    }
+
+    /// The latest `limit` messages of `channel` in the store, oldest first.
+    pub fn stored_messages(&self, channel: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        match self.store.lock().unwrap().as_ref() {
+            Some(store) => store.channel(channel, 0, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Send `text` only to the node called `name`. If it's offline but was seen before, the message waits in the
+    /// outbox until there's a link to it again. Either way, it's confirmed with an [`Event::Delivered`] once received.
+    pub async fn send_private(&self, name: &str, text: String) -> Result<Outgoing, SendError> {
+        let connected = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .find(|peer| peer.name == name)
+            .map(|peer| (peer.key, peer.outbound.clone()));
+        let to = match &connected {
+            Some((key, _)) => *key,
+            None => self
+                .keys
+                .get(name)
+                .ok_or_else(|| SendError::Unknown(name.to_owned()))?,
+        };
+        let id = self.next_private.fetch_add(1, Ordering::Relaxed);
+        let message = PrivateMessage {
+            id,
+            author: self.name.clone(),
+            text,
+        };
+        self.outbox.lock().unwrap().push(to, message.clone());
+        let sent = match connected {
+            Some((_, outbound)) => outbound.send(Frame::Private(message)).await.is_ok(),
+            None => false,
+        };
+
+        Ok(Outgoing {
+            to,
+            id,
+            queued: !sent,
+        })
+    }
+
+    /// Only accept streams, and dial peers, as `policy` allows, from now on.
+    pub fn set_admission(&self, policy: AdmissionPolicy) {
+        self.admission.set_policy(policy);
+    }
+
+    /// Act as a seed: tell every new peer about the others, so that nodes which can't discover each other, like
+    /// those of different sites, link to each other through this one.
+    pub fn set_seed(&self, seed: bool) {
+        self.seed.store(seed, Ordering::Relaxed);
+    }
+
+    /// Apply `policy` to the links opened from now on.
+    pub fn set_queue_policy(&self, policy: QueuePolicy) {
+        *self.queue_policy.lock().unwrap() = policy;
+    }
+
+    /// How full the outbound queue of each link is.
+    pub fn queue_stats(&self) -> HashMap<PeerId, QueueStats> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, peer)| (*id, peer.outbound.stats()))
+            .collect()
+    }
+
+    /// Keep the private messages waiting for their recipients in `outbox`, sending those already in it once their
+    /// recipients are online.
+    pub fn attach_outbox(&self, outbox: Outbox) {
+        if let Some(last) = outbox.last_id() {
+            self.next_private.fetch_max(last + 1, Ordering::Relaxed);
+        }
+        *self.outbox.lock().unwrap() = outbox;
+    }
+
+    /// Offer the file at `path` to the node called `name`, which has to be connected.
+    pub async fn offer_file(&self, name: &str, path: &Path) -> Result<FileOffer> {
+        let (key, outbound) = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .find(|peer| peer.name == name)
+            .map(|peer| (peer.key, peer.outbound.clone()))
+            .ok_or_else(|| SendError::NotConnected(name.to_owned()))?;
+        let id = self.next_transfer.fetch_add(1, Ordering::Relaxed);
+        let offer = {
+            let path = path.to_owned();
+            tokio::task::spawn_blocking(move || FileOffer::new(id, &path)).await??
+        };
+        self.transfers
+            .lock()
+            .unwrap()
+            .outgoing
+            .insert((key, id), (offer.clone(), path.to_owned()));
+        outbound
+            .send(Frame::Offer(offer.clone()))
+            .await
+            .map_err(|_| SendError::NotConnected(name.to_owned()))?;
+
+        Ok(offer)
+    }
+
+    /// Accept the file `from` offered with this id, saving it to `dest`. Returns how much of it was already there
+    /// from an earlier, interrupted, transfer.
+    pub async fn accept_file(&self, from: PeerId, id: u64, dest: PathBuf) -> Result<u64> {
+        let (key, outbound) = self
+            .link(from)
+            .ok_or_else(|| SendError::NotConnected(from.to_string()))?;
+        let incoming = self
+            .transfers
+            .lock()
+            .unwrap()
+            .incoming
+            .get(&(key, id))
+            .cloned()
+            .ok_or_else(|| anyhow!("{from} didn't offer a file with id {id}"))?;
+        let offset =
+            tokio::task::spawn_blocking(move || incoming.lock().unwrap().accept(dest)).await??;
+        outbound
+            .send(Frame::Accept { id, offset })
+            .await
+            .map_err(|_| SendError::NotConnected(from.to_string()))?;
+
+        Ok(offset)
+    }
+
+    /// Decline the file `from` offered with this id.
+    pub async fn decline_file(&self, from: PeerId, id: u64) -> Result<()> {
+        let (key, outbound) = self
+            .link(from)
+            .ok_or_else(|| SendError::NotConnected(from.to_string()))?;
+        self.transfers
+            .lock()
+            .unwrap()
+            .incoming
+            .remove(&(key, id))
+            .ok_or_else(|| anyhow!("{from} didn't offer a file with id {id}"))?;
+        let _ = outbound.send(Frame::Decline { id }).await;
+
+        Ok(())
+    }
+
+    /// Send the file at `path` to `peer` from `offset` on, until it's all sent or the link breaks.
+    async fn stream_file(
+        self,
+        peer: PeerId,
+        outbound: Mux,
+        offer: FileOffer,
+        path: PathBuf,
+        offset: u64,
+    ) {
+        let mut offset = offset;
+        let mut sent = false;
+        loop {
+            let chunk = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || read_chunk(&path, offset)).await
+            };
+            let data = match chunk.map_err(anyhow::Error::from).and_then(|read| read) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Couldn't read {}: {e}", path.display());
+                    break;
+                }
+            };
+            // Even a file with nothing left to send gets a chunk, for the other side to finish it.
+            if data.is_empty() && sent {
+                break;
+            }
+            sent = true;
+            let len = data.len() as u64;
+            let chunk = Frame::Chunk {
+                id: offer.id,
+                offset,
+                data,
+            };
+            if outbound.send(chunk).await.is_err() {
+                info!("Link to {peer} broke while sending {}", offer.name);
+                break;
+            }
+            if len == 0 {
+                break;
+            }
+            offset += len;
+            let _ = self.events.send(Event::TransferProgress {
+                peer,
+                id: offer.id,
+                done: offset,
+                size: offer.size,
+            });
+        }
+    }
+
+    fn link(&self, id: PeerId) -> Option<(PublicKey, Mux)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|peer| (peer.key, peer.outbound.clone()))
+    }
+
+    /// Close the link to `peer`, if there is one.
+    pub fn disconnect(&self, peer: PeerId) {
+        self.wanted.lock().unwrap().remove(&peer);
+        self.reachable_at.lock().unwrap().remove(&peer);
+        self.announced_keys.lock().unwrap().remove(&peer);
+        let removed = self.peers.lock().unwrap().remove(&peer);
+        if let Some(removed) = removed {
+            self.closed(peer, removed);
+        }
+    }
+
+    /// Close every link.
+    pub fn disconnect_all(&self) {
+        self.wanted.lock().unwrap().clear();
+        self.reachable_at.lock().unwrap().clear();
+        self.announced_keys.lock().unwrap().clear();
+        let peers: Vec<_> = self.peers.lock().unwrap().drain().collect();
+        for (id, peer) in peers {
+            self.closed(id, peer);
+        }
+    }
+
+    fn outbound(&self, id: PeerId) -> Option<Mux> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|peer| peer.outbound.clone())
+    }
+
+    fn closed(&self, id: PeerId, peer: Peer) {
+        info!("Closed link to {} ({id})", peer.name);
+        peer.reader.abort();
+        peer.outbound.close();
+        self.forget_subscriptions(id);
+        let _ = self.events.send(Event::PeerDisconnected { peer: id });
+    }
+
+    /// Secure `stream`, exchange [`Frame::Challenge`]s and [`Frame::Hello`]s over it and keep it as the link to that
+    /// peer, if it's in the same room.
+    ///
+    /// A link dialed to reach `peer` is kept as the link to it, whatever address the other side is seen at. Any other
+    /// is kept as the link to the id announced in the hello, as discovery knows it by that.
+    ///
+    /// Should both nodes dial each other at the same time, both ends keep the link opened by the node with the lower
+    /// address, so that they agree on which one to drop.
+    ///
+    /// Returns once the link is closed, and whether it was kept.
+    async fn add_stream(self, stream: TcpStream, peer: Option<PeerId>) -> Result<bool> {
+        let dialed = peer.is_some();
+        let ip = match stream.peer_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => return Err(anyhow!("IPV6 not implemented.")),
+        };
+        let challenge: [u8; 32] = rand::random();
+        let greeting = async {
+            let (mut reader, mut writer, signer) =
+                secure::handshake(stream.into_split(), dialed, &self.name, &self.identity).await?;
+
+            writer.send(&Frame::Challenge { nonce: challenge }).await?;
+            let Frame::Challenge { nonce } = reader.recv().await? else {
+                return Err(anyhow!("Peer didn't start with a challenge"));
+            };
+
+            let room_proof = self
+                .room
+                .as_ref()
+                .map(|room| room.mac(&[ROOM_PROOF_CONTEXT, &nonce, &self.identity.public_key().0]));
+            writer
+                .send(&Frame::Hello {
+                    id: self.me,
+                    room_proof,
+                    channels: self.channels().into_iter().collect(),
+                })
+                .await?;
+            let hello = reader.recv().await?;
+
+            Ok((reader, writer, signer, hello))
+        };
+        let (reader, mut writer, signer, hello) = tokio::time::timeout(HANDSHAKE_TIMEOUT, greeting)
+            .await
+            .map_err(|_| anyhow!("Handshake with {ip} timed out"))??;
+        let Frame::Hello {
+            id: announced,
+            room_proof,
+            channels,
+        } = hello
+        else {
+            return Err(anyhow!("Peer didn't answer the challenge with a hello"));
+        };
+        let name = signer.name.clone();
+        match (&self.room, room_proof) {
+            (None, None) => {}
+            (Some(room), Some(proof))
+                if room.verify(&[ROOM_PROOF_CONTEXT, &challenge, &signer.key.0], &proof) => {}
+            _ => return Err(anyhow!("{name} isn't in the same room")),
+        }
+        // Only once the room is checked, so that outsiders can't claim names.
+        self.keys.check(&signer)?;
+        // A node listening on every interface knows itself by no address in particular.
+        let announced = if announced.ip().is_unspecified() {
+            SocketAddrV4::new(ip, announced.port())
+        } else {
+            announced
+        };
+        if let Some(peer) = peer
+            && self.reachable_at.lock().unwrap().contains_key(&peer)
+        {
+            // Endpoints can be announced by anyone for any id, so only the node going by it may answer at them.
+            if announced != peer {
+                return Err(anyhow!(
+                    "{name} answered for {peer}, but goes by {announced}"
+                ));
+            }
+            if let Some(key) = self.announced_keys.lock().unwrap().get(&peer)
+                && *key != signer.key
+            {
+                return Err(anyhow!(
+                    "{name} answered for {peer}, but not with the key it was announced with"
+                ));
+            }
+        }
+        let id = peer.unwrap_or(announced);
+        if id == self.me {
+            return Ok(false);
+        }
+
+        let outbound = Mux::new(*self.queue_policy.lock().unwrap());
+        let link = self.next_link.fetch_add(1, Ordering::Relaxed);
+        let preferred = dialed == (self.me < id);
+        let replaced = {
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(existing) = peers.get(&id)
+                && (!preferred || existing.dialed == dialed)
+            {
+                info!("Already connected to {id}. Dropping duplicate link.");
+                return Ok(false);
+            }
+            self.set_subscriptions(id, channels);
+            let manager = self.clone();
+            let mux = outbound.clone();
+            let reader = tokio::spawn(async move {
+                manager.read_frames(id, signer.key, link, mux, reader).await
+            });
+            peers.insert(
+                id,
+                Peer {
+                    link,
+                    dialed,
+                    name: name.clone(),
+                    key: signer.key,
+                    outbound: outbound.clone(),
+                    reader: reader.abort_handle(),
+                },
+            )
+        };
+        if let Some(replaced) = replaced {
+            info!("Replacing duplicate link to {id}.");
+            replaced.reader.abort();
+            replaced.outbound.close();
+        } else {
+            info!("Established TCP connection to {name} ({id})");
+            self.link_state(id, LinkState::Up);
+            let _ = self.events.send(Event::PeerConnected {
+                peer: id,
+                name,
+                key: signer.key,
+            });
+        }
+
+        let queued = self.outbox.lock().unwrap().queued(&signer.key);
+        if !queued.is_empty() {
+            info!("Sending {} queued private messages to {id}", queued.len());
+        }
+        let others = self.seed.load(Ordering::Relaxed).then(|| {
+            let peers = self.peers.lock().unwrap();
+            let others = peers.keys().filter(|peer| **peer != id).copied();
+            Frame::Peers(others.take(MAX_SHARED_PEERS).collect())
+        });
+        let summary = self.history.lock().unwrap().summary();
+        let first = outbound.clone();
+        tokio::spawn(async move {
+            for frame in queued.into_iter().map(Frame::Private).chain(others) {
+                if first.send(frame).await.is_err() {
+                    return;
+                }
+            }
+            let _ = first.send(Frame::Summary(summary)).await;
+        });
+        while let Some(frame) = outbound.next_frame().await {
+            if let Err(e) = writer.send(&frame).await {
+                info!("Error writing to {id}: {e}");
+                break;
+            }
+        }
+        self.unlink(id, link);
+
+        Ok(true)
+    }
+
+    async fn read_frames(
+        self,
+        id: PeerId,
+        key: PublicKey,
+        link: u64,
+        mux: Mux,
+        mut reader: SecureReader<OwnedReadHalf>,
+    ) {
+        loop {
+            let received = reader
+                .recv::<MuxFrame>()
+                .await
+                .and_then(|frame| mux.receive(frame));
+            match received {
+                Ok(Some((stream, len, frame))) => {
+                    self.handle(id, key, frame).await;
+                    mux.consumed(stream, len);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    info!("Link to {id} broke: {e}");
+                    break;
+                }
+            }
+        }
+
+        self.unlink(id, link);
+    }
+
+    /// Close the link to `id`, unless it was already replaced by a newer one.
+    fn unlink(&self, id: PeerId, link: u64) {
+        let removed = {
+            let mut peers = self.peers.lock().unwrap();
+            match peers.get(&id) {
+                Some(peer) if peer.link == link => peers.remove(&id),
+                _ => None,
+            }
+        };
+        if let Some(removed) = removed {
+            self.closed(id, removed);
+        }
+    }
+
+    /// Act on a frame received from `id`.
+    async fn handle(&self, id: PeerId, key: PublicKey, frame: Frame) {
+        match frame {
+            Frame::Chat(mut message) => {
+                if !self.seen.lock().unwrap().insert(message.id) {
+                    return;
+                }
+                // Relayed whether or not this node is in the channel, as it may be the only way there.
+                if message.ttl > 1 {
+                    let mut relayed = message.clone();
+                    relayed.ttl -= 1;
+                    self.send_to_others(Frame::Chat(relayed), Some(id)).await;
+                }
+                message.ttl = message.ttl.saturating_sub(1);
+                if self.channels.borrow().contains(&message.channel) {
+                    self.deliver(
+                        self.causal
+                            .lock()
+                            .unwrap()
+                            .receive(id, message, Instant::now()),
+                    );
+                } else {
+                    debug!(
+                        "Dropping message from {id} to #{}, which we're not in",
+                        message.channel
+                    );
+                }
+            }
+            Frame::Subscriptions(channels) => self.set_subscriptions(id, channels),
+            Frame::Private(message) => {
+                let name = self
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .filter(|peer| peer.key == key)
+                    .map(|peer| peer.name.clone());
+                let Some(name) = name.filter(|name| *name == message.author) else {
+                    warn!(
+                        "Dropping private message from {id} claiming to be from {}",
+                        message.author
+                    );
+                    return;
+                };
+                if let Some(outbound) = self.outbound(id) {
+                    let _ = outbound.send(Frame::Ack { id: message.id }).await;
+                }
+                let first = self.seen_private.lock().unwrap().insert(MessageId {
+                    origin: key,
+                    seq: message.id,
+                });
+                if first {
+                    let _ = self.events.send(Event::PrivateMessage {
+                        from: id,
+                        name,
+                        key,
+                        message,
+                    });
+                }
+            }
+            Frame::Summary(summary) => {
+                let channels = self
+                    .subscriptions
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or_default();
+                let missing = self
+                    .history
+                    .lock()
+                    .unwrap()
+                    .missing(&summary, |channel| channels.contains(channel));
+                let outbound = self.outbound(id);
+                if let Some(outbound) = outbound {
+                    for batch in missing.chunks(HISTORY_BATCH) {
+                        let _ = outbound.send(Frame::History(batch.to_vec())).await;
+                    }
+                }
+            }
+            Frame::History(messages) => {
+                let messages: Vec<_> = {
+                    let channels = self.channels.borrow();
+                    let mut seen = self.seen.lock().unwrap();
+                    messages
+                        .into_iter()
+                        .filter(|message| {
+                            channels.contains(&message.channel) && seen.insert(message.id)
+                        })
+                        .map(|message| (id, message))
+                        .collect()
+                };
+                debug!("Caught up on {} messages from {id}", messages.len());
+                self.deliver(
+                    self.causal
+                        .lock()
+                        .unwrap()
+                        .catch_up(messages, Instant::now()),
+                );
+            }
+            Frame::Ack { id: acked } => {
+                self.outbox.lock().unwrap().acknowledge(&key, acked);
+                let _ = self.events.send(Event::Delivered {
+                    peer: id,
+                    id: acked,
+                });
+            }
+            Frame::Offer(offer) => {
+                info!("{id} offers {} ({} bytes)", offer.name, offer.size);
+                self.transfers.lock().unwrap().incoming.insert(
+                    (key, offer.id),
+                    Arc::new(Mutex::new(Incoming::new(offer.clone()))),
+                );
+                let _ = self.events.send(Event::FileOffered { from: id, offer });
+            }
+            Frame::Accept {
+                id: offered,
+                offset,
+            } => {
+                let mut transfers = self.transfers.lock().unwrap();
+                let outgoing = transfers.outgoing.get(&(key, offered)).cloned();
+                match (outgoing, self.outbound(id)) {
+                    _ if transfers
+                        .sending
+                        .get(&(key, offered))
+                        .is_some_and(|sending| !sending.is_finished()) =>
+                    {
+                        debug!("{id} accepted {offered} again while it's being sent");
+                    }
+                    (Some((offer, path)), Some(outbound)) => {
+                        let sending = tokio::spawn(
+                            self.clone().stream_file(id, outbound, offer, path, offset),
+                        );
+                        transfers
+                            .sending
+                            .insert((key, offered), sending.abort_handle());
+                    }
+                    _ => debug!("{id} accepted unknown file {offered}"),
+                }
+            }
+            Frame::Decline { id: offered } => {
+                let mut transfers = self.transfers.lock().unwrap();
+                transfers.outgoing.remove(&(key, offered));
+                if let Some(sending) = transfers.sending.remove(&(key, offered)) {
+                    sending.abort();
+                }
+                drop(transfers);
+                let _ = self.events.send(Event::FileDeclined {
+                    peer: id,
+                    id: offered,
+                });
+            }
+            Frame::Chunk {
+                id: offered,
+                offset,
+                data,
+            } => {
+                let incoming = self
+                    .transfers
+                    .lock()
+                    .unwrap()
+                    .incoming
+                    .get(&(key, offered))
+                    .cloned();
+                let written = match incoming {
+                    Some(incoming) => tokio::task::spawn_blocking(move || {
+                        let mut incoming = incoming.lock().unwrap();
+                        incoming
+                            .write(offset, &data)
+                            .map(|verified| (verified, incoming.received(), incoming.offer.size))
+                    })
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|written| written),
+                    None => Err(anyhow!("unknown file {offered}")),
+                };
+                if matches!(written, Ok((Some(_), ..))) {
+                    self.transfers
+                        .lock()
+                        .unwrap()
+                        .incoming
+                        .remove(&(key, offered));
+                }
+                match written {
+                    Ok((None, done, size)) => {
+                        let _ = self.events.send(Event::TransferProgress {
+                            peer: id,
+                            id: offered,
+                            done,
+                            size,
+                        });
+                    }
+                    Ok((Some(verified), ..)) => {
+                        if let Some(outbound) = self.outbound(id) {
+                            let _ = outbound
+                                .send(Frame::Received {
+                                    id: offered,
+                                    verified,
+                                })
+                                .await;
+                        }
+                        let _ = self.events.send(Event::TransferFinished {
+                            peer: id,
+                            id: offered,
+                            verified,
+                        });
+                    }
+                    Err(e) => warn!("Dropping chunk from {id}: {e}"),
+                }
+            }
+            Frame::Received {
+                id: offered,
+                verified,
+            } => {
+                let mut transfers = self.transfers.lock().unwrap();
+                transfers.outgoing.remove(&(key, offered));
+                transfers.sending.remove(&(key, offered));
+                drop(transfers);
+                let _ = self.events.send(Event::TransferFinished {
+                    peer: id,
+                    id: offered,
+                    verified,
+                });
+            }
+            Frame::Peers(peers) => {
+                // Only seeds share peers, and only those dialed on purpose are trusted to be seeds.
+                let dialed = self
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .is_some_and(|peer| peer.dialed);
+                if !dialed || self.wanted.lock().unwrap().get(&id) != Some(&Want::Always) {
+                    debug!("Ignoring peers shared by {id}, which wasn't configured");
+                    return;
+                }
+                for peer in peers.into_iter().take(MAX_SHARED_PEERS) {
+                    if peer == self.me || self.is_connected(peer) || self.wants(peer) {
+                        continue;
+                    }
+                    let shared = self
+                        .wanted
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .filter(|want| **want == Want::Shared)
+                        .count();
+                    if shared >= MAX_SHARED_PEERS {
+                        debug!("Already dialing {shared} shared peers. Ignoring the rest.");
+                        break;
+                    }
+                    info!("{id} shared {peer}");
+                    self.want(peer, Want::Shared);
+                }
+            }
+            Frame::Challenge { .. } | Frame::Hello { .. } => {
+                info!("Ignoring repeated handshake from {id}")
+            }
+        }
+    }
+}
+
+fn log_link_error(e: &anyhow::Error) {
+    if let Some(e) = e.downcast_ref::<TrustError>() {
+        warn!(
+            "Refusing link: {e}. If the key change is expected, remove the old key from the key store."
+        );
+    } else {
+        info!("Couldn't establish link: {e}");
+    }
+}
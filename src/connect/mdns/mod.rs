@@ -0,0 +1,614 @@
+//! Discovery over mDNS/DNS-SD, for networks which drop the multicast group of [`MulticastServer`] but let mDNS
+//! through.
+//!
+//! Each node advertises a `_chat-async._tcp.local` service instance named after a random id, with an SRV record for
+//! its server and a TXT record holding the rest of its [`Announce`](MulticastMessage::Announce) and its channels.
+//! Records received are turned back into the [`MulticastMessage`]s a [`MulticastServer`] would have received, so
+//! that [`discover_peers`](super::discovery::discover_peers) handles both the same way.
+//!
+//! [`MulticastServer`]: super::multicast::server::MulticastServer
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{Result, anyhow};
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use tracing::{info, trace, warn};
+
+use crate::{
+    connect::multicast::{
+        communicator::{Communicator, SocketCommunicator},
+        limit::RateLimiter,
+        message::MulticastMessage,
+        server::{MAX_CLOCK_SKEW, Received, Security, now_millis},
+    },
+    room::RoomKey,
+};
+
+use packet::{Data, Packet, Question, Record, TYPE_ANY, TYPE_PTR};
+
+pub mod packet;
+
+/// The group and port of mDNS.
+pub const MDNS_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// The DNS-SD service nodes advertise.
+pub const SERVICE: &str = "_chat-async._tcp.local";
+
+/// Seconds other nodes may remember the records of this one for.
+pub const TTL: u32 = 120;
+
+/// Longest string a TXT record can hold.
+const MAX_TXT_LEN: usize = 255;
+
+/// What this node advertises, as learnt from the messages [`discover_peers`](super::discovery::discover_peers)
+/// sends.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Advert {
+    id: Option<SocketAddrV4>,
+    port: u16,
+    name: String,
+    endpoints: Vec<SocketAddr>,
+    features: Vec<String>,
+    channels: Vec<String>,
+}
+
+/// Advertises this node and browses for others over mDNS. See the [module documentation](self).
+#[derive(Debug)]
+pub struct MdnsServer<C: Communicator = SocketCommunicator> {
+    communicator: Arc<C>,
+    /// Names this node's service instance, and stands for the [`sender`](Received::sender) of its records.
+    id: u64,
+    advert: Advert,
+    room: Option<RoomKey>,
+    receiver: JoinHandle<Result<()>>,
+}
+
+impl<C: Communicator> MdnsServer<C> {
+    /// Join mDNS at `address` (normally [`MDNS_ADDRESS`]) and browse for other nodes, sending every message their
+    /// records stand for through `msg_sender`.
+    ///
+    /// Records can't be signed, so this fails in [`strict`](Security::strict) mode. In a [`room`](Security::room),
+    /// they carry a timestamp and a tag over their contents only nodes in the room can make, and those of other
+    /// rooms, altered or too old are ignored.
+    #[tracing::instrument(skip(msg_sender, security))]
+    pub async fn join(
+        address: SocketAddrV4,
+        msg_sender: Sender<Received<MulticastMessage>>,
+        security: Security,
+    ) -> Result<Self> {
+        if security.strict {
+            return Err(anyhow!(
+                "mDNS records can't be signed, as strict mode requires"
+            ));
+        }
+        let communicator = Arc::new(C::try_from_socket_addr(address).await?);
+
+        let mut server = Self {
+            id: rand::random(),
+            receiver: tokio::spawn(Self::receive(
+                communicator.clone(),
+                msg_sender,
+                security.clone(),
+            )),
+            communicator,
+            advert: Advert::default(),
+            room: security.room,
+        };
+        server.send(MulticastMessage::Join).await?;
+
+        Ok(server)
+    }
+
+    /// Send what `msg` stands for: a query for the other nodes for a [`Join`](MulticastMessage::Join), the records
+    /// of this node withdrawn for a [`CloseServer`](MulticastMessage::CloseServer), or its records updated with the
+    /// contents of any other message.
+    #[tracing::instrument(name = "MdnsServer::send", skip(self))]
+    pub async fn send(&mut self, msg: MulticastMessage) -> Result<()> {
+        let mut ttl = TTL;
+        match msg {
+            MulticastMessage::Join => {
+                // This node's own instance, as a known answer, tells the queries of nodes on the same host apart.
+                let query = Packet {
+                    response: false,
+                    questions: vec![Question {
+                        name: SERVICE.to_owned(),
+                        kind: TYPE_PTR,
+                    }],
+                    records: vec![Record {
+                        name: SERVICE.to_owned(),
+                        ttl: TTL,
+                        data: Data::Ptr(instance(self.id)),
+                    }],
+                };
+                self.communicator.communicate(&query.encode()?).await?;
+                return Ok(());
+            }
+            MulticastMessage::NewServer { port } => self.advert.port = port,
+            MulticastMessage::CloseServer { port } => {
+                self.advert.port = port;
+                ttl = 0;
+            }
+            MulticastMessage::Channels { port, channels } => {
+                self.advert.port = port;
+                self.advert.channels = channels;
+            }
+            MulticastMessage::Announce {
+                id,
+                name,
+                endpoints,
+                features,
+            } => {
+                self.advert = Advert {
+                    id: Some(id),
+                    port: id.port(),
+                    name,
+                    endpoints,
+                    features,
+                    channels: std::mem::take(&mut self.advert.channels),
+                };
+            }
+        }
+        let Some(records) = records(self.id, &self.advert, ttl, self.room.as_ref()) else {
+            trace!("Nothing to advertise until the node is announced.");
+            return Ok(());
+        };
+        let response = Packet {
+            response: true,
+            questions: Vec::new(),
+            records,
+        };
+        self.communicator.communicate(&response.encode()?).await?;
+
+        Ok(())
+    }
+
+    /// Read from mDNS until `sender` is closed, forwarding the messages the records of other nodes stand for.
+    #[tracing::instrument(name = "MdnsServer::receive", skip_all)]
+    async fn receive(
+        communicator: Arc<C>,
+        sender: Sender<Received<MulticastMessage>>,
+        security: Security,
+    ) -> Result<()> {
+        let mut buf = [0; 9000];
+        let mut limiter = RateLimiter::new(security.rate_limit);
+        loop {
+            let (len, source) = tokio::select! {
+                res = communicator.receive(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Error receiving from mDNS: {e}");
+                        continue;
+                    }
+                },
+                () = sender.closed() => {
+                    info!("Message channel was closed. Will stop receiving from mDNS.");
+                    break;
+                }
+            };
+            if !limiter.allow(source.ip(), Instant::now()) {
+                trace!("Dropping packet from {source}, which sends too many.");
+                continue;
+            }
+            let packet = match Packet::decode(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    trace!("Ignoring bad packet from {source}: {e}");
+                    continue;
+                }
+            };
+            for (id, message) in messages(&packet, security.room.as_ref()) {
+                let received = Received {
+                    message,
+                    source,
+                    signer: None,
+                    sender: id,
+                    legacy: false,
+                };
+                if sender.send(received).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<C: Communicator> Drop for MdnsServer<C> {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// The name of the service instance with `id`.
+fn instance(id: u64) -> String {
+    format!("{id:016x}.{SERVICE}")
+}
+
+/// The id of `instance`, if it's named like those of [`instance`].
+fn instance_id(instance: &str) -> Option<u64> {
+    let label = instance.strip_suffix(SERVICE)?.strip_suffix('.')?;
+    u64::from_str_radix(label, 16).ok()
+}
+
+/// What the room tag of the records of `instance` covers: the port, TTL and TXT strings they hold, but the tag.
+fn tagged_bytes(instance: &str, port: u16, ttl: u32, txt: &[&str]) -> Vec<u8> {
+    let mut bytes = b"mdns".to_vec();
+    bytes.extend_from_slice(&port.to_be_bytes());
+    bytes.extend_from_slice(&ttl.to_be_bytes());
+    for part in [instance].iter().chain(txt) {
+        bytes.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(part.as_bytes());
+    }
+
+    bytes
+}
+
+/// `features` as `features=` TXT strings, split so that none is too long for a TXT record.
+fn features_txt(features: &[String]) -> Vec<String> {
+    let mut txt = vec!["features=".to_owned()];
+    for feature in features {
+        let last = txt.last_mut().expect("there's always a string");
+        if last.len() + 1 + feature.len() <= MAX_TXT_LEN {
+            if !last.ends_with('=') {
+                last.push(',');
+            }
+            last.push_str(feature);
+        } else if "features=".len() + feature.len() <= MAX_TXT_LEN {
+            txt.push(format!("features={feature}"));
+        } else {
+            warn!("Feature {feature:?} is too long to advertise.");
+        }
+    }
+
+    txt
+}
+
+/// The records advertising `advert` as the instance with `id`, if it was announced.
+fn records(id: u64, advert: &Advert, ttl: u32, room: Option<&RoomKey>) -> Option<Vec<Record>> {
+    let me = advert.id?;
+    let instance = instance(id);
+    let host = format!("{id:016x}.local");
+    let mut txt = vec![format!("id={me}"), format!("name={}", advert.name)];
+    txt.extend(
+        advert
+            .endpoints
+            .iter()
+            .map(|endpoint| format!("ep={endpoint}")),
+    );
+    txt.extend(features_txt(&advert.features));
+    txt.extend(
+        advert
+            .channels
+            .iter()
+            .map(|channel| format!("ch={channel}")),
+    );
+    txt.retain(|string| {
+        let fits = string.len() <= MAX_TXT_LEN;
+        if !fits {
+            warn!("Leaving {string:?} out of the TXT record, which can't hold it.");
+        }
+        fits
+    });
+    if let Some(room) = room {
+        txt.push(format!("t={}", now_millis()));
+        let strings: Vec<&str> = txt.iter().map(String::as_str).collect();
+        let tag = room.mac(&[&tagged_bytes(&instance, advert.port, ttl, &strings)]);
+        txt.push(format!("room={}", hex::encode(tag)));
+    }
+
+    let record = |name: &str, data| Record {
+        name: name.to_owned(),
+        ttl,
+        data,
+    };
+    let mut records = vec![
+        record(SERVICE, Data::Ptr(instance.clone())),
+        record(
+            &instance,
+            Data::Srv {
+                port: advert.port,
+                target: host.clone(),
+            },
+        ),
+        record(&instance, Data::Txt(txt)),
+        record(&host, Data::A(*me.ip())),
+    ];
+    for endpoint in &advert.endpoints {
+        let data = match endpoint {
+            SocketAddr::V4(addr) if addr.ip() != me.ip() => Data::A(*addr.ip()),
+            // Links are only ever made over IPv4 for now.
+            _ => continue,
+        };
+        records.push(record(&host, data));
+    }
+
+    Some(records)
+}
+
+/// The messages the records in `packet` stand for, along with the id of the instance each comes from.
+fn messages(packet: &Packet, room: Option<&RoomKey>) -> Vec<(u64, MulticastMessage)> {
+    if !packet.response {
+        let browsing = packet.questions.iter().any(|question| {
+            question.name == SERVICE && matches!(question.kind, TYPE_PTR | TYPE_ANY)
+        });
+        let querier = packet.records.iter().find_map(|record| match &record.data {
+            Data::Ptr(instance) if record.name == SERVICE => instance_id(instance),
+            _ => None,
+        });
+        return if browsing {
+            vec![(querier.unwrap_or(0), MulticastMessage::Join)]
+        } else {
+            Vec::new()
+        };
+    }
+
+    let mut messages = Vec::new();
+    let suffix = format!(".{SERVICE}");
+    for srv in &packet.records {
+        let (Data::Srv { port, target }, Some(label)) = (&srv.data, srv.name.strip_suffix(&suffix))
+        else {
+            continue;
+        };
+        let id = instance_id(&srv.name).unwrap_or(0);
+        let strings: Vec<&str> = packet
+            .records
+            .iter()
+            .filter(|record| record.name == srv.name)
+            .filter_map(|record| match &record.data {
+                Data::Txt(strings) => Some(strings),
+                _ => None,
+            })
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        let mut txt: HashMap<&str, Vec<&str>> = HashMap::new();
+        for string in &strings {
+            let (key, value) = string.split_once('=').unwrap_or((string, ""));
+            txt.entry(key).or_default().push(value);
+        }
+        let values = |key| txt.get(key).cloned().unwrap_or_default();
+        let tag = values("room")
+            .first()
+            .and_then(|tag| <[u8; 32]>::try_from(hex::decode(tag).ok()?).ok());
+        match (room, tag) {
+            (None, None) => {}
+            (Some(room), Some(tag)) => {
+                let tagged: Vec<&str> = strings
+                    .iter()
+                    .copied()
+                    .filter(|string| !string.starts_with("room="))
+                    .collect();
+                if !room.verify(&[&tagged_bytes(&srv.name, *port, srv.ttl, &tagged)], &tag) {
+                    trace!("Ignoring {}, from another room or altered.", srv.name);
+                    continue;
+                }
+                let sent = values("t").first().and_then(|t| t.parse::<u64>().ok());
+                if sent.is_none_or(|sent| {
+                    now_millis().abs_diff(sent) > MAX_CLOCK_SKEW.as_millis() as u64
+                }) {
+                    trace!("Ignoring {}, whose records are too old.", srv.name);
+                    continue;
+                }
+            }
+            _ => {
+                trace!("Ignoring {} from another room.", srv.name);
+                continue;
+            }
+        }
+        if srv.ttl == 0 {
+            messages.push((id, MulticastMessage::CloseServer { port: *port }));
+            continue;
+        }
+
+        let addresses: Vec<SocketAddr> = packet
+            .records
+            .iter()
+            .filter(|record| record.name == *target)
+            .filter_map(|record| match record.data {
+                Data::A(ip) => Some(SocketAddr::new(ip.into(), *port)),
+                _ => None,
+            })
+            .collect();
+        let announced = values("id").first().and_then(|id| id.parse().ok());
+        let me = announced.or_else(|| {
+            addresses.iter().find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(*addr),
+                SocketAddr::V6(_) => None,
+            })
+        });
+        if let Some(me) = me {
+            let endpoints: Vec<SocketAddr> = values("ep")
+                .into_iter()
+                .filter_map(|endpoint| endpoint.parse().ok())
+                .collect();
+            let name = values("name").first().copied().unwrap_or(label);
+            let features = values("features")
+                .into_iter()
+                .flat_map(|features| features.split(','))
+                .filter(|feature| !feature.is_empty())
+                .map(ToOwned::to_owned)
+                .collect();
+            messages.push((
+                id,
+                MulticastMessage::Announce {
+                    id: me,
+                    name: name.to_owned(),
+                    endpoints: if endpoints.is_empty() {
+                        addresses
+                    } else {
+                        endpoints
+                    },
+                    features,
+                },
+            ));
+        }
+        messages.push((id, MulticastMessage::NewServer { port: *port }));
+        let channels = values("ch").into_iter().map(ToOwned::to_owned).collect();
+        messages.push((
+            id,
+            MulticastMessage::Channels {
+                port: *port,
+                channels,
+            },
+        ));
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advert() -> Advert {
+        Advert {
+            id: Some("192.168.1.20:4321".parse().unwrap()),
+            port: 4321,
+            name: "alice".to_owned(),
+            endpoints: vec![
+                "203.0.113.7:4000".parse().unwrap(),
+                "[2001:db8::7]:4000".parse().unwrap(),
+            ],
+            features: vec!["mux".to_owned(), "files".to_owned()],
+            channels: vec!["general".to_owned(), "rust".to_owned()],
+        }
+    }
+
+    fn response(records: Vec<Record>) -> Packet {
+        let packet = Packet {
+            response: true,
+            questions: Vec::new(),
+            records,
+        };
+        Packet::decode(&packet.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn records_stand_for_what_was_announced() {
+        let advert = advert();
+        let packet = response(records(7, &advert, TTL, None).unwrap());
+        let goodbye = response(records(7, &advert, 0, None).unwrap());
+
+        assert_eq!(
+            messages(&packet, None),
+            [
+                (
+                    7,
+                    MulticastMessage::Announce {
+                        id: advert.id.unwrap(),
+                        name: advert.name,
+                        endpoints: advert.endpoints,
+                        features: advert.features,
+                    }
+                ),
+                (7, MulticastMessage::NewServer { port: 4321 }),
+                (
+                    7,
+                    MulticastMessage::Channels {
+                        port: 4321,
+                        channels: advert.channels,
+                    }
+                ),
+            ]
+        );
+
+        assert_eq!(
+            messages(&goodbye, None),
+            [(7, MulticastMessage::CloseServer { port: 4321 })]
+        );
+    }
+
+    #[test]
+    fn queries_stand_for_joins() {
+        let mut packet = Packet {
+            response: false,
+            questions: vec![Question {
+                name: SERVICE.to_owned(),
+                kind: TYPE_PTR,
+            }],
+            records: Vec::new(),
+        };
+        assert_eq!(messages(&packet, None), [(0, MulticastMessage::Join)]);
+
+        packet.records.push(Record {
+            name: SERVICE.to_owned(),
+            ttl: TTL,
+            data: Data::Ptr(instance(7)),
+        });
+        assert_eq!(messages(&packet, None), [(7, MulticastMessage::Join)]);
+
+        packet.questions[0].name = "_http._tcp.local".to_owned();
+        assert!(messages(&packet, None).is_empty());
+    }
+
+    #[test]
+    fn records_of_other_rooms_are_ignored() {
+        let room = RoomKey::from_passphrase("fourth floor");
+        let packet = response(records(7, &advert(), TTL, Some(&room)).unwrap());
+
+        assert_eq!(messages(&packet, Some(&room)).len(), 3);
+        assert!(messages(&packet, None).is_empty());
+        let other = RoomKey::from_passphrase("fifth floor");
+        assert!(messages(&packet, Some(&other)).is_empty());
+    }
+
+    #[test]
+    fn altered_records_are_ignored() {
+        let room = RoomKey::from_passphrase("fourth floor");
+        let mut packet = response(records(7, &advert(), TTL, Some(&room)).unwrap());
+        for record in &mut packet.records {
+            if let Data::Txt(strings) = &mut record.data {
+                for string in strings
+                    .iter_mut()
+                    .filter(|string| string.starts_with("ep="))
+                {
+                    *string = "ep=198.51.100.66:4000".to_owned();
+                }
+            }
+        }
+
+        assert!(messages(&packet, Some(&room)).is_empty());
+
+        // Tagged properly, but long ago.
+        let txt = ["id=192.168.1.20:4321", "t=1000"];
+        let tag = room.mac(&[&tagged_bytes(&instance(7), 4321, TTL, &txt)]);
+        for record in &mut packet.records {
+            if let Data::Txt(strings) = &mut record.data {
+                *strings = txt.iter().map(|string| string.to_string()).collect();
+                strings.push(format!("room={}", hex::encode(tag)));
+            }
+        }
+        assert!(messages(&packet, Some(&room)).is_empty());
+    }
+
+    #[test]
+    fn long_txt_strings_are_split_or_left_out() {
+        let mut advert = advert();
+        advert.features = (0..40).map(|i| format!("feature{i:02}")).collect();
+        advert.channels.push("x".repeat(300));
+        let packet = response(records(7, &advert, TTL, None).unwrap());
+
+        let [
+            (_, MulticastMessage::Announce { features, .. }),
+            _,
+            (_, channels),
+        ] = &messages(&packet, None)[..]
+        else {
+            panic!("records don't stand for an announcement");
+        };
+        assert_eq!(*features, advert.features);
+        assert_eq!(
+            *channels,
+            MulticastMessage::Channels {
+                port: 4321,
+                channels: vec!["general".to_owned(), "rust".to_owned()],
+            }
+        );
+    }
+}
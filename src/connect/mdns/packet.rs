@@ -0,0 +1,344 @@
+//! The parts of the DNS message format (RFC 1035) that DNS-SD over mDNS needs.
+//!
+//! Names are kept as dotted strings, so labels containing dots can't be told apart from several labels. Names are
+//! never compressed when encoding, but compressed names are understood when decoding.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Result, anyhow};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Top bit of the class: "unicast response" in a question, "cache flush" in a record. Ignored by this module.
+const CLASS_FLAG: u16 = 0x8000;
+
+/// Flags of a response: the QR bit and AA, as mDNS responses are always authoritative.
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// How many compression pointers may be followed in a single name, so that loops end.
+const MAX_JUMPS: usize = 16;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Packet {
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// The records of the answer, authority and additional sections, which mDNS doesn't treat differently.
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub kind: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    /// Seconds the record may be cached for. 0 withdraws it.
+    pub ttl: u32,
+    pub data: Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Data {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv {
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<String>),
+    /// A record of some other type, which is skipped.
+    Other(u16),
+}
+
+impl Data {
+    fn kind(&self) -> u16 {
+        match self {
+            Self::A(_) => TYPE_A,
+            Self::Aaaa(_) => TYPE_AAAA,
+            Self::Ptr(_) => TYPE_PTR,
+            Self::Srv { .. } => TYPE_SRV,
+            Self::Txt(_) => TYPE_TXT,
+            Self::Other(kind) => *kind,
+        }
+    }
+}
+
+impl Packet {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(512);
+        // mDNS messages have an id of 0.
+        put_u16(&mut bytes, 0);
+        put_u16(&mut bytes, if self.response { FLAGS_RESPONSE } else { 0 });
+        put_u16(&mut bytes, self.questions.len().try_into()?);
+        put_u16(&mut bytes, self.records.len().try_into()?);
+        put_u16(&mut bytes, 0);
+        put_u16(&mut bytes, 0);
+        for question in &self.questions {
+            put_name(&mut bytes, &question.name)?;
+            put_u16(&mut bytes, question.kind);
+            put_u16(&mut bytes, CLASS_IN);
+        }
+        for record in &self.records {
+            put_name(&mut bytes, &record.name)?;
+            put_u16(&mut bytes, record.data.kind());
+            put_u16(&mut bytes, CLASS_IN);
+            bytes.extend_from_slice(&record.ttl.to_be_bytes());
+            let start = bytes.len();
+            put_u16(&mut bytes, 0);
+            match &record.data {
+                Data::A(ip) => bytes.extend_from_slice(&ip.octets()),
+                Data::Aaaa(ip) => bytes.extend_from_slice(&ip.octets()),
+                Data::Ptr(name) => put_name(&mut bytes, name)?,
+                Data::Srv { port, target } => {
+                    // Priority and weight, which only matter with several targets.
+                    put_u16(&mut bytes, 0);
+                    put_u16(&mut bytes, 0);
+                    put_u16(&mut bytes, *port);
+                    put_name(&mut bytes, target)?;
+                }
+                Data::Txt(strings) => {
+                    for string in strings {
+                        let len: u8 = string
+                            .len()
+                            .try_into()
+                            .map_err(|_| anyhow!("TXT string of {} bytes", string.len()))?;
+                        bytes.push(len);
+                        bytes.extend_from_slice(string.as_bytes());
+                    }
+                    // An empty TXT record still holds a single empty string.
+                    if strings.is_empty() {
+                        bytes.push(0);
+                    }
+                }
+                Data::Other(kind) => return Err(anyhow!("Can't encode records of type {kind}")),
+            }
+            let len: u16 = (bytes.len() - start - 2).try_into()?;
+            bytes[start..start + 2].copy_from_slice(&len.to_be_bytes());
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let _id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+
+        let mut packet = Self {
+            response: flags & 0x8000 != 0,
+            ..Default::default()
+        };
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let kind = reader.u16()?;
+            let _class = reader.u16()? & !CLASS_FLAG;
+            packet.questions.push(Question { name, kind });
+        }
+        for _ in 0..records {
+            let name = reader.name()?;
+            let kind = reader.u16()?;
+            let _class = reader.u16()? & !CLASS_FLAG;
+            let ttl = u32::from_be_bytes(reader.take(4)?.try_into()?);
+            let len = reader.u16()? as usize;
+            let end = reader.pos + len;
+            let data = match kind {
+                TYPE_A => Data::A(<[u8; 4]>::try_from(reader.take(len)?)?.into()),
+                TYPE_AAAA => Data::Aaaa(<[u8; 16]>::try_from(reader.take(len)?)?.into()),
+                TYPE_PTR => Data::Ptr(reader.name()?),
+                TYPE_SRV => {
+                    let _priority = reader.u16()?;
+                    let _weight = reader.u16()?;
+                    let port = reader.u16()?;
+                    let target = reader.name()?;
+                    Data::Srv { port, target }
+                }
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    let mut txt = Reader {
+                        bytes: reader.take(len)?,
+                        pos: 0,
+                    };
+                    while txt.pos < txt.bytes.len() {
+                        let len = txt.take(1)?[0] as usize;
+                        if len > 0 {
+                            strings.push(String::from_utf8_lossy(txt.take(len)?).into_owned());
+                        }
+                    }
+                    Data::Txt(strings)
+                }
+                other => {
+                    reader.take(len)?;
+                    Data::Other(other)
+                }
+            };
+            if reader.pos != end {
+                return Err(anyhow!(
+                    "Record of type {kind} doesn't fill its {len} bytes"
+                ));
+            }
+            packet.records.push(Record { name, ttl, data });
+        }
+
+        Ok(packet)
+    }
+}
+
+fn put_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_name(bytes: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return Err(anyhow!("Label {label:?} is longer than 63 bytes"));
+        }
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let taken = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("Packet ends early"))?;
+        self.pos += len;
+
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    /// Read a name, following compression pointers.
+    fn name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        // Where reading goes on from once the name is read, if it jumped elsewhere.
+        let mut resume = None;
+        for _ in 0..=MAX_JUMPS {
+            loop {
+                let len = *self
+                    .bytes
+                    .get(pos)
+                    .ok_or_else(|| anyhow!("Packet ends early"))?
+                    as usize;
+                if len & 0xc0 == 0xc0 {
+                    let low = *self
+                        .bytes
+                        .get(pos + 1)
+                        .ok_or_else(|| anyhow!("Packet ends early"))?;
+                    resume.get_or_insert(pos + 2);
+                    pos = (len & 0x3f) << 8 | low as usize;
+                    break;
+                }
+                pos += 1;
+                if len == 0 {
+                    self.pos = resume.unwrap_or(pos);
+                    return Ok(labels.join("."));
+                }
+                let label = self
+                    .bytes
+                    .get(pos..pos + len)
+                    .ok_or_else(|| anyhow!("Packet ends early"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += len;
+            }
+        }
+
+        Err(anyhow!("Too many compression pointers in a name"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_survive_a_round_trip() {
+        let packet = Packet {
+            response: true,
+            questions: vec![Question {
+                name: "_chat-async._tcp.local".to_owned(),
+                kind: TYPE_PTR,
+            }],
+            records: vec![
+                Record {
+                    name: "_chat-async._tcp.local".to_owned(),
+                    ttl: 120,
+                    data: Data::Ptr("0123._chat-async._tcp.local".to_owned()),
+                },
+                Record {
+                    name: "0123._chat-async._tcp.local".to_owned(),
+                    ttl: 120,
+                    data: Data::Srv {
+                        port: 4321,
+                        target: "0123.local".to_owned(),
+                    },
+                },
+                Record {
+                    name: "0123._chat-async._tcp.local".to_owned(),
+                    ttl: 0,
+                    data: Data::Txt(vec!["name=alice".to_owned(), "ch=rust".to_owned()]),
+                },
+                Record {
+                    name: "0123.local".to_owned(),
+                    ttl: 120,
+                    data: Data::A(Ipv4Addr::new(192, 168, 1, 20)),
+                },
+                Record {
+                    name: "0123.local".to_owned(),
+                    ttl: 120,
+                    data: Data::Aaaa(Ipv6Addr::LOCALHOST),
+                },
+            ],
+        };
+
+        assert_eq!(Packet::decode(&packet.encode().unwrap()).unwrap(), packet);
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        let mut bytes = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        // A PTR record for "_tcp.local", at 12, to a name ending with a pointer back to it.
+        bytes.extend_from_slice(b"\x04_tcp\x05local\x00");
+        bytes.extend_from_slice(&[0, 12, 0x80, 1, 0, 0, 0, 120, 0, 6]);
+        bytes.extend_from_slice(b"\x03abc\xc0\x0c");
+
+        let packet = Packet::decode(&bytes).unwrap();
+        assert_eq!(packet.records[0].name, "_tcp.local");
+        assert_eq!(
+            packet.records[0].data,
+            Data::Ptr("abc._tcp.local".to_owned())
+        );
+
+        // A pointer to itself never ends.
+        let mut looping = bytes[..12].to_vec();
+        looping[7] = 0;
+        looping[5] = 1;
+        looping.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+        assert!(Packet::decode(&looping).is_err());
+    }
+}
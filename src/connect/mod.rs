@@ -1,14 +1,16 @@
-use anyhow::Result;
-use tokio::{net::TcpStream, sync::mpsc::Receiver};
-use tracing::info;
-
+pub mod admission;
+pub mod backoff;
+pub mod causal;
+pub mod dial;
+pub mod discovery;
+pub mod frame;
 pub mod get_my_ip;
+pub mod gossip;
+pub mod history;
+pub mod manager;
+pub mod mdns;
 pub mod multicast;
-
-pub async fn manage_tcp_streams(mut rx: Receiver<TcpStream>) -> Result<()> {
-    while let Some(stream) = rx.recv().await {
-        info!("Established TCP connection to {}", stream.peer_addr()?.ip());
-    }
-
-    anyhow::Ok(())
-}
+pub mod mux;
+pub mod outbox;
+pub mod secure;
+pub mod transfer;
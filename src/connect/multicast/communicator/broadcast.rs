@@ -0,0 +1,105 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::info;
+
+use super::Communicator;
+
+/// Talks to every node on the local network through UDP broadcast, for networks which don't forward multicast.
+///
+/// Joined to a broadcast address, like the subnet-directed `192.168.1.255:4983`, datagrams are sent to it. Joined to
+/// a multicast address, they're sent to the limited broadcast address, `255.255.255.255`, on the same port, so that
+/// a [`MulticastServer`](crate::connect::multicast::server::MulticastServer) works unchanged with either.
+#[derive(Debug)]
+pub struct BroadcastCommunicator {
+    socket: UdpSocket,
+    address: SocketAddrV4,
+}
+
+impl super::AsyncTryFromSocketAddr for BroadcastCommunicator {
+    #[tracing::instrument(name = "Enter Broadcast")]
+    async fn try_from_socket_addr(addr: SocketAddrV4) -> Result<Self> {
+        let address = if addr.ip().is_multicast() {
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, addr.port())
+        } else {
+            addr
+        };
+        info!("Broadcasting to {address}");
+        // Reuse the address so that every node on this computer receives the broadcasts.
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        // Linux otherwise hands it the datagrams of every multicast group joined on this port, by any socket.
+        #[cfg(target_os = "linux")]
+        socket.set_multicast_all_v4(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, address.port()).into())?;
+
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            address,
+        })
+    }
+}
+
+impl Communicator for BroadcastCommunicator {
+    async fn communicate(&self, bytes: &[u8]) -> Result<usize, io::Error> {
+        self.socket.send_to(bytes, self.address).await
+    }
+
+    async fn receive(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), io::Error> {
+        match self.socket.recv_from(buf).await? {
+            (len, SocketAddr::V4(peer)) => Ok((len, peer)),
+            (_, SocketAddr::V6(_)) => Err(io::Error::other("IPV6 not implemented.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect::multicast::{AsyncTryFromSocketAddr, join::connect_to_multicast};
+
+    #[tokio::test]
+    async fn every_member_hears_a_broadcast() {
+        // The subnet-directed broadcast address of loopback.
+        let address = SocketAddrV4::new(Ipv4Addr::new(127, 255, 255, 255), 34983);
+        let a = BroadcastCommunicator::try_from_socket_addr(address)
+            .await
+            .unwrap();
+        let b = BroadcastCommunicator::try_from_socket_addr(address)
+            .await
+            .unwrap();
+
+        a.communicate(b"hello").await.unwrap();
+        for member in [&a, &b] {
+            let mut buf = [0; 16];
+            let (len, _) = member.receive(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn hears_only_broadcasts_next_to_a_multicast_group() {
+        let group = SocketAddrV4::new(Ipv4Addr::new(224, 0, 9, 2), 34984);
+        let multicast = connect_to_multicast(group).await.unwrap();
+        let broadcast = BroadcastCommunicator::try_from_socket_addr(group)
+            .await
+            .unwrap();
+
+        multicast.send_to(b"multicast", group).await.unwrap();
+        let loopback = SocketAddrV4::new(Ipv4Addr::new(127, 255, 255, 255), group.port());
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        sender.set_broadcast(true).unwrap();
+        sender.send_to(b"broadcast", loopback).await.unwrap();
+
+        let mut buf = [0; 16];
+        let (len, _) = broadcast.receive(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"broadcast");
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::SocketAddrV4,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::{
+    sync::mpsc::{self, Receiver},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use super::{AsyncTryFromSocketAddr, BroadcastCommunicator, Communicator, SocketCommunicator};
+
+/// How long a [`FallbackCommunicator`] waits to hear another node through its primary communicator before falling
+/// back.
+pub const FALLBACK_WINDOW: Duration = Duration::from_secs(3);
+
+/// How many of the last datagrams sent are remembered, to tell them apart from those of others when they loop back.
+const SENT_LEN: usize = 64;
+
+type Datagram = (Vec<u8>, SocketAddrV4);
+
+/// Talks through a `primary` communicator, falling back to another one if no other node is heard through it within
+/// a startup window, as on networks which don't forward multicast.
+///
+/// Datagrams are sent through both during the window, and received from both all along. Once it's over, they're
+/// sent through every communicator another node was heard through, or only through the fallback if none was.
+#[derive(Debug)]
+pub struct FallbackCommunicator<P = SocketCommunicator, F = BroadcastCommunicator> {
+    primary: Arc<P>,
+    fallback: Arc<F>,
+    until: Instant,
+    /// Whether another node was heard through the primary and the fallback communicator.
+    heard: Arc<[AtomicBool; 2]>,
+    /// Hashes of the last datagrams sent.
+    sent: Arc<Mutex<VecDeque<u64>>>,
+    fell_back: AtomicBool,
+    received: tokio::sync::Mutex<Receiver<Datagram>>,
+    receivers: [JoinHandle<()>; 2],
+}
+
+impl<P: Communicator, F: Communicator> FallbackCommunicator<P, F> {
+    /// Talk through `primary`, falling back to `fallback` if no one is heard through it within `window`.
+    pub fn new(primary: P, fallback: F, window: Duration) -> Self {
+        let (primary, fallback) = (Arc::new(primary), Arc::new(fallback));
+        let heard: Arc<[AtomicBool; 2]> = Default::default();
+        let sent: Arc<Mutex<VecDeque<u64>>> = Default::default();
+        let (tx, rx) = mpsc::channel(64);
+        let receivers = [
+            tokio::spawn(forward(
+                primary.clone(),
+                0,
+                heard.clone(),
+                sent.clone(),
+                tx.clone(),
+            )),
+            tokio::spawn(forward(
+                fallback.clone(),
+                1,
+                heard.clone(),
+                sent.clone(),
+                tx,
+            )),
+        ];
+
+        Self {
+            primary,
+            fallback,
+            until: Instant::now() + window,
+            heard,
+            sent,
+            fell_back: AtomicBool::new(false),
+            received: tokio::sync::Mutex::new(rx),
+            receivers,
+        }
+    }
+
+    /// Whether datagrams are still sent through the primary communicator, and through the fallback.
+    fn sending(&self) -> (bool, bool) {
+        if Instant::now() < self.until {
+            return (true, true);
+        }
+        let heard = self
+            .heard
+            .each_ref()
+            .map(|heard| heard.load(Ordering::Relaxed));
+        if heard == [false, false] {
+            if !self.fell_back.swap(true, Ordering::Relaxed) {
+                warn!("No one heard through the primary communicator. Falling back.");
+            }
+            return (false, true);
+        }
+
+        (heard[0], heard[1])
+    }
+}
+
+/// Receive through `communicator` until it fails, forwarding datagrams to `received` and noting in `heard` whether
+/// one didn't come from this node.
+async fn forward<C: Communicator>(
+    communicator: Arc<C>,
+    index: usize,
+    heard: Arc<[AtomicBool; 2]>,
+    sent: Arc<Mutex<VecDeque<u64>>>,
+    received: mpsc::Sender<Datagram>,
+) {
+    let mut buf = [0; 9000];
+    loop {
+        let (len, source) = match communicator.receive(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                info!("Stopped receiving through {communicator:?}: {e}");
+                return;
+            }
+        };
+        if !sent.lock().unwrap().contains(&hash(&buf[..len])) {
+            heard[index].store(true, Ordering::Relaxed);
+        }
+        if received.send((buf[..len].to_vec(), source)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<P: Communicator, F: Communicator> AsyncTryFromSocketAddr for FallbackCommunicator<P, F> {
+    async fn try_from_socket_addr(addr: SocketAddrV4) -> Result<Self> {
+        let primary = P::try_from_socket_addr(addr).await?;
+        let fallback = F::try_from_socket_addr(addr).await?;
+
+        Ok(Self::new(primary, fallback, FALLBACK_WINDOW))
+    }
+}
+
+impl<P: Communicator, F: Communicator> Communicator for FallbackCommunicator<P, F> {
+    async fn communicate(&self, bytes: &[u8]) -> Result<usize, io::Error> {
+        {
+            let mut sent = self.sent.lock().unwrap();
+            if sent.len() == SENT_LEN {
+                sent.pop_front();
+            }
+            sent.push_back(hash(bytes));
+        }
+        let (primary, fallback) = self.sending();
+        let mut result = Ok(bytes.len());
+        if primary && let Err(e) = self.primary.communicate(bytes).await {
+            result = Err(e);
+        }
+        if fallback {
+            // One of them getting through is enough.
+            match self.fallback.communicate(bytes).await {
+                Ok(len) => result = Ok(len),
+                Err(e) if !primary => result = Err(e),
+                Err(e) => info!("Couldn't send through the fallback: {e}"),
+            }
+        }
+
+        result
+    }
+
+    async fn receive(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), io::Error> {
+        let (datagram, source) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("Both communicators stopped receiving"))?;
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+
+        Ok((len, source))
+    }
+}
+
+impl<P, F> Drop for FallbackCommunicator<P, F> {
+    fn drop(&mut self) {
+        for receiver in &self.receivers {
+            receiver.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::connect::multicast::communicator::MemoryCommunicator;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    async fn member(port: u16) -> MemoryCommunicator {
+        MemoryCommunicator::try_from_socket_addr(SocketAddrV4::new(
+            Ipv4Addr::new(224, 0, 9, 1),
+            port,
+        ))
+        .await
+        .unwrap()
+    }
+
+    /// Whether `member` receives a datagram within a short while.
+    async fn hears(member: &MemoryCommunicator) -> bool {
+        let mut buf = [0; 64];
+        tokio::time::timeout(Duration::from_millis(50), member.receive(&mut buf))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_no_one_is_heard() {
+        let communicator = FallbackCommunicator::new(member(1).await, member(2).await, WINDOW);
+        let fallback_peer = member(2).await;
+
+        tokio::time::sleep(WINDOW).await;
+        communicator.communicate(b"anyone?").await.unwrap();
+        assert!(hears(&fallback_peer).await);
+    }
+
+    #[tokio::test]
+    async fn stays_on_the_primary_when_someone_is_heard_through_it() {
+        let communicator = FallbackCommunicator::new(member(3).await, member(4).await, WINDOW);
+        let primary_peer = member(3).await;
+        let fallback_peer = member(4).await;
+
+        primary_peer.communicate(b"here").await.unwrap();
+        let mut buf = [0; 64];
+        communicator.receive(&mut buf).await.unwrap();
+        tokio::time::sleep(WINDOW).await;
+        while hears(&primary_peer).await {}
+
+        communicator.communicate(b"hello").await.unwrap();
+        assert!(hears(&primary_peer).await);
+        assert!(!hears(&fallback_peer).await);
+    }
+}
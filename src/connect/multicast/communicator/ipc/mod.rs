@@ -1,25 +1,37 @@
 use anyhow::Result;
 use interprocess::local_socket::{
-    GenericNamespaced, Name, Stream as IpcStream, ToNsName, traits::Stream,
+    GenericNamespaced, Name, ToNsName,
+    tokio::{RecvHalf, SendHalf, Stream as IpcStream},
+    traits::tokio::Stream,
 };
-use tokio::join;
+use tokio::{io::AsyncReadExt, sync::Mutex};
 
 use std::{
     fmt::Debug,
-    io::{self, Write},
-    net::SocketAddrV4,
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
 };
 use tracing::info;
 
-use crate::connect::multicast::AsyncTryFromSocketAddr;
+use crate::connect::{
+    frame::{read_frame, write_frame},
+    multicast::AsyncTryFromSocketAddr,
+};
 
 use super::Communicator;
 
 mod spawn_process;
 
+/// Talks to the multicast through a separate communicator process, which is shared by every [`IpcCommunicator`]
+/// on this computer that is connected to the same multicast address.
+///
+/// Messages to the communicator process are framed as `[len: u32][payload]`. Messages from it also carry who sent
+/// the datagram: `[ip: 4 bytes][port: u16][len: u32][payload]`. Everything is big endian.
 #[derive(Debug)]
 pub struct IpcCommunicator {
-    ipc_conn: IpcStream,
+    recv: Mutex<RecvHalf>,
+    send: Mutex<SendHalf>,
     multicast_addr: SocketAddrV4,
 }
 
@@ -30,8 +42,8 @@ impl IpcCommunicator {
             .unwrap()
     }
 
-    fn connect_to_ipc_stream(multicast_addr: SocketAddrV4) -> Result<IpcStream> {
-        let ipc_conn = match IpcStream::connect(Self::local_socket_name(multicast_addr)) {
+    async fn connect_to_ipc_stream(multicast_addr: SocketAddrV4) -> Result<IpcStream> {
+        let ipc_conn = match IpcStream::connect(Self::local_socket_name(multicast_addr)).await {
             Ok(ipc_conn) => ipc_conn,
             Err(e) => {
                 info!(
@@ -42,10 +54,11 @@ impl IpcCommunicator {
 
                 loop {
                     if let Ok(ipc_conn) =
-                        IpcStream::connect(Self::local_socket_name(multicast_addr))
+                        IpcStream::connect(Self::local_socket_name(multicast_addr)).await
                     {
                         break ipc_conn;
                     }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
                 }
             }
         };
@@ -57,13 +70,12 @@ impl IpcCommunicator {
 
 impl AsyncTryFromSocketAddr for IpcCommunicator {
     #[tracing::instrument]
-    fn try_from_socket_addr(addr: SocketAddrV4) -> impl Future<Output = Result<Self>> {
-        async {
-            let ipc_connect = tokio::spawn(async move { Self::connect_to_ipc_stream(addr) });
-
-            let (ipc_connect,) = join!(ipc_connect);
+    fn try_from_socket_addr(addr: SocketAddrV4) -> impl Future<Output = Result<Self>> + Send {
+        async move {
+            let (recv, send) = Self::connect_to_ipc_stream(addr).await?.split();
             Ok(Self {
-                ipc_conn: ipc_connect??,
+                recv: Mutex::new(recv),
+                send: Mutex::new(send),
                 multicast_addr: addr,
             })
         }
@@ -71,9 +83,18 @@ impl AsyncTryFromSocketAddr for IpcCommunicator {
 }
 
 impl Communicator for IpcCommunicator {
-    async fn communicate(&mut self, bytes: &[u8]) -> Result<usize, io::Error> {
-        info!("Communicating: {bytes:?}");
-        self.ipc_conn.write_all(bytes).unwrap();
-        Ok(0)
+    async fn communicate(&self, bytes: &[u8]) -> Result<usize, io::Error> {
+        info!("Communicating to {}: {bytes:?}", self.multicast_addr);
+        write_frame(&mut *self.send.lock().await, bytes).await?;
+        Ok(bytes.len())
+    }
+
+    async fn receive(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), io::Error> {
+        let mut recv = self.recv.lock().await;
+        let ip = Ipv4Addr::from(recv.read_u32().await?);
+        let port = recv.read_u16().await?;
+        let len = read_frame(&mut *recv, buf).await?;
+
+        Ok((len, SocketAddrV4::new(ip, port)))
     }
 }
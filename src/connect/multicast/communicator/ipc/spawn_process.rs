@@ -1,25 +1,22 @@
 use std::{
-    net::SocketAddrV4,
+    net::{SocketAddr, SocketAddrV4},
     sync::{Arc, atomic::AtomicUsize},
     time::Duration,
 };
 
-use interprocess::{
-    bound_util::RefTokioAsyncRead,
-    local_socket::{
-        self, ListenerOptions,
-        traits::tokio::{Listener, Stream},
-    },
+use interprocess::local_socket::{
+    self, ListenerOptions,
+    tokio::SendHalf,
+    traits::tokio::{Listener, Stream},
 };
 use procspawn::JoinHandle;
-use tokio::{
-    io::AsyncReadExt,
-    select,
-    sync::mpsc::{self, Sender},
-};
+use tokio::{io::AsyncWriteExt, net::UdpSocket, sync::Mutex};
 use tracing::{info, warn};
 
-use crate::connect::multicast::{communicator::error, join::connect_to_multicast};
+use crate::connect::{
+    frame::read_frame,
+    multicast::{communicator::error, join::connect_to_multicast},
+};
 
 use super::IpcCommunicator;
 
@@ -59,63 +56,32 @@ impl IpcCommunicator {
             std::process::id()
         );
 
-        let multicast_connection = connect_to_multicast(multicast_addr)
-            .await
-            .inspect_err(|e| tracing::error!("Error connecting to multicast: {e}"))
-            .unwrap();
-
-        let (t_receiver, mut r_receiver) = mpsc::channel(8);
-        let (t_sender, mut r_sender) = mpsc::channel(8);
-
-        tokio::spawn(Self::accept_ipc_connections(listener, t_sender, t_receiver));
-
-        tokio::spawn(async move {
-            // This has to be a BytesMut. For some reason, a simple Vec<u8> *does not work*
-            let mut buf = bytes::BytesMut::with_capacity(4096);
-            let mut receivers = Vec::new();
-            let mut remove = Vec::new();
-            loop {
-                if let Ok(rec) = r_receiver.try_recv() {
-                    receivers.push(rec);
-                }
-
-                for (i, rec) in receivers.iter_mut().enumerate() {
-                    match rec.as_tokio_async_read().read_buf(&mut buf).await {
-                        Ok(0) => {
-                            info!("IPC connection closed. Removing from list.");
-                            IPC_CONNECTIONS.fetch_sub(1, std::sync::atomic::Ordering::Release);
-                            remove.push(i);
-                        }
-                        Ok(_) => {
-                            info!("Received message from IPC connection: {:?}", &buf[..])
-                        }
-                        Err(e) => tracing::error!("Error reading from IPC connection: {e}"),
-                    }
-                }
+        let multicast_connection = Arc::new(
+            connect_to_multicast(multicast_addr)
+                .await
+                .inspect_err(|e| tracing::error!("Error connecting to multicast: {e}"))
+                .unwrap(),
+        );
+        let clients = Arc::new(Mutex::new(Vec::new()));
 
-                for i in remove.drain(..) {
-                    receivers.remove(i);
-                }
-                // Needed because otherwise this may spin-loop and block the checker for no tasks, in case it's scheduled for the same thread.
-                if receivers.is_empty() {
-                    tokio::task::yield_now().await;
-                }
-            }
-        });
+        tokio::spawn(Self::accept_ipc_connections(
+            listener,
+            multicast_connection.clone(),
+            multicast_addr,
+            clients.clone(),
+        ));
+        tokio::spawn(Self::forward_multicast(multicast_connection, clients));
 
         let mut interval = tokio::time::interval(Duration::from_secs(10));
         interval.tick().await;
         let check_end = tokio::spawn(async move {
             loop {
+                interval.tick().await;
                 if IPC_CONNECTIONS.load(std::sync::atomic::Ordering::Acquire) == 0 {
-                    interval.tick().await;
-                    if IPC_CONNECTIONS.load(std::sync::atomic::Ordering::Acquire) == 0 {
-                        info!("10 seconds without any connections. Quitting multicast process...");
-                        break;
-                    }
+                    info!("10 seconds without any connections. Quitting multicast process...");
+                    break;
                 }
             }
-            tokio::task::yield_now().await;
         });
 
         check_end.await.unwrap();
@@ -125,8 +91,9 @@ impl IpcCommunicator {
 
     async fn accept_ipc_connections(
         listener: local_socket::tokio::Listener,
-        t_sender: Sender<local_socket::tokio::SendHalf>,
-        t_receiver: Sender<local_socket::tokio::RecvHalf>,
+        multicast: Arc<UdpSocket>,
+        multicast_addr: SocketAddrV4,
+        clients: Arc<Mutex<Vec<SendHalf>>>,
     ) {
         loop {
             let conn = match listener.accept().await {
@@ -139,9 +106,62 @@ impl IpcCommunicator {
             info!("New IPC connection");
 
             IPC_CONNECTIONS.fetch_add(1, std::sync::atomic::Ordering::Release);
-            let (r_end, s_end) = conn.split();
-            t_receiver.send(r_end).await.unwrap();
-            t_sender.send(s_end).await.unwrap();
+            let (mut r_end, s_end) = conn.split();
+            clients.lock().await.push(s_end);
+
+            let multicast = multicast.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 4096];
+                loop {
+                    match read_frame(&mut r_end, &mut buf).await {
+                        Ok(len) => {
+                            info!("Received message from IPC connection: {:?}", &buf[..len]);
+                            if let Err(e) = multicast.send_to(&buf[..len], multicast_addr).await {
+                                tracing::error!("Error sending to multicast: {e}");
+                            }
+                        }
+                        Err(e) => {
+                            info!("IPC connection closed ({e}). Removing from list.");
+                            IPC_CONNECTIONS.fetch_sub(1, std::sync::atomic::Ordering::Release);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Send every datagram received from the multicast to all of the IPC connections, dropping the ones that fail.
+    async fn forward_multicast(multicast: Arc<UdpSocket>, clients: Arc<Mutex<Vec<SendHalf>>>) {
+        let mut buf = [0; 4096];
+        loop {
+            let (len, peer) = match multicast.recv_from(&mut buf).await {
+                Ok((len, SocketAddr::V4(peer))) => (len, peer),
+                Ok((_, SocketAddr::V6(peer))) => {
+                    warn!("Ignoring multicast message from IPV6 address {peer}");
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!("Error receiving from multicast: {e}");
+                    continue;
+                }
+            };
+            let frame = [
+                &peer.ip().octets()[..],
+                &peer.port().to_be_bytes(),
+                &(len as u32).to_be_bytes(),
+                &buf[..len],
+            ]
+            .concat();
+
+            let mut clients = clients.lock().await;
+            let mut alive = Vec::with_capacity(clients.len());
+            for mut client in clients.drain(..) {
+                if client.write_all(&frame).await.is_ok() {
+                    alive.push(client);
+                }
+            }
+            *clients = alive;
         }
     }
 }
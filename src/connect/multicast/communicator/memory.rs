@@ -0,0 +1,309 @@
+//! A [`Communicator`] that never touches the network, for deterministic testing.
+//!
+//! Every [`MemoryCommunicator`] joined to the same address shares an in-process hub, which delivers each datagram to
+//! all of its members (the sender included, like a multicast with loopback enabled). Faults can be injected on a hub
+//! with [`MemoryCommunicator::set_faults`].
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU16, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{AsyncTryFromSocketAddr, Communicator};
+
+type Datagram = (Vec<u8>, SocketAddrV4);
+
+static HUBS: LazyLock<Mutex<HashMap<SocketAddrV4, Arc<Mutex<Hub>>>>> =
+    LazyLock::new(Default::default);
+
+/// Each member gets a distinct port on localhost as its source address.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+/// Faults injected on every datagram delivered by a hub. Probabilities are applied independently to each recipient.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Probability of a datagram being dropped.
+    pub loss: f64,
+    /// Probability of a datagram being delivered twice.
+    pub duplication: f64,
+    /// Probability of a datagram being held back and delivered after the next one.
+    pub reordering: f64,
+    /// Delay before a datagram is delivered.
+    pub latency: Duration,
+    /// Seed for the random decisions, so that a run can be reproduced.
+    pub seed: u64,
+}
+
+#[derive(Debug)]
+struct Member {
+    address: SocketAddrV4,
+    sender: UnboundedSender<Datagram>,
+    held_back: Option<Datagram>,
+}
+
+#[derive(Debug)]
+struct Hub {
+    members: Vec<Member>,
+    faults: Faults,
+    rng: StdRng,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self {
+            members: Vec::new(),
+            faults: Faults::default(),
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+}
+
+impl Hub {
+    fn get(addr: SocketAddrV4) -> Arc<Mutex<Hub>> {
+        HUBS.lock().unwrap().entry(addr).or_default().clone()
+    }
+
+    fn broadcast(&mut self, bytes: &[u8], source: SocketAddrV4) {
+        let Faults {
+            loss,
+            duplication,
+            reordering,
+            latency,
+            ..
+        } = self.faults.clone();
+
+        for member in &mut self.members {
+            if self.rng.gen_bool(loss) {
+                continue;
+            }
+            let mut datagrams = vec![(bytes.to_vec(), source)];
+            if self.rng.gen_bool(duplication) {
+                datagrams.push((bytes.to_vec(), source));
+            }
+            if self.rng.gen_bool(reordering) && member.held_back.is_none() {
+                member.held_back = datagrams.pop();
+            }
+            if !datagrams.is_empty() {
+                datagrams.extend(member.held_back.take());
+            }
+
+            for datagram in datagrams {
+                let sender = member.sender.clone();
+                if latency.is_zero() {
+                    let _ = sender.send(datagram);
+                } else {
+                    tokio::spawn(async move {
+                        tokio::time::sleep(latency).await;
+                        let _ = sender.send(datagram);
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// An in-memory stand-in for a multicast socket. See the [module documentation](self).
+#[derive(Debug)]
+pub struct MemoryCommunicator {
+    hub: Arc<Mutex<Hub>>,
+    address: SocketAddrV4,
+    receiver: tokio::sync::Mutex<UnboundedReceiver<Datagram>>,
+}
+
+impl MemoryCommunicator {
+    /// Inject `faults` on the hub of the multicast at `addr`, replacing any previous ones.
+    pub fn set_faults(addr: SocketAddrV4, faults: Faults) {
+        let hub = Hub::get(addr);
+        let mut hub = hub.lock().unwrap();
+        hub.rng = StdRng::seed_from_u64(faults.seed);
+        hub.faults = faults;
+    }
+
+    /// The address other members see as the source of this communicator's datagrams.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.address
+    }
+}
+
+impl AsyncTryFromSocketAddr for MemoryCommunicator {
+    async fn try_from_socket_addr(addr: SocketAddrV4) -> Result<Self> {
+        let hub = Hub::get(addr);
+        let address = SocketAddrV4::new(
+            Ipv4Addr::LOCALHOST,
+            NEXT_PORT.fetch_add(1, Ordering::Relaxed),
+        );
+        let (sender, receiver) = mpsc::unbounded_channel();
+        hub.lock().unwrap().members.push(Member {
+            address,
+            sender,
+            held_back: None,
+        });
+
+        Ok(Self {
+            hub,
+            address,
+            receiver: tokio::sync::Mutex::new(receiver),
+        })
+    }
+}
+
+impl Communicator for MemoryCommunicator {
+    async fn communicate(&self, bytes: &[u8]) -> Result<usize, io::Error> {
+        self.hub.lock().unwrap().broadcast(bytes, self.address);
+        Ok(bytes.len())
+    }
+
+    async fn receive(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), io::Error> {
+        let (bytes, source) = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Hub was closed"))?;
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+
+        Ok((len, source))
+    }
+}
+
+impl Drop for MemoryCommunicator {
+    fn drop(&mut self) {
+        if let Ok(mut hub) = self.hub.lock() {
+            hub.members.retain(|member| member.address != self.address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::connect::multicast::{
+        message::MulticastMessage,
+        server::{MulticastServer, Received},
+    };
+
+    use super::*;
+
+    type Server = MulticastServer<MulticastMessage, MemoryCommunicator>;
+
+    /// Each test gets its own hub, so they can run in parallel.
+    fn address(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 1), port)
+    }
+
+    #[tokio::test]
+    async fn delivers_to_every_member() {
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, mut rx_b) = mpsc::channel(8);
+        let mut a = Server::join(address(1), tx_a).await.unwrap();
+        let _b = Server::join(address(1), tx_b).await.unwrap();
+
+        a.send(MulticastMessage::NewServer { port: 1234 })
+            .await
+            .unwrap();
+
+        // `a` sees its own join, `b`'s join and the announcement. `b` only sees its own join and the announcement.
+        let mut received_a = Vec::new();
+        for _ in 0..3 {
+            received_a.push(rx_a.recv().await.unwrap().message);
+        }
+        assert_eq!(
+            received_a,
+            [
+                MulticastMessage::Join,
+                MulticastMessage::Join,
+                MulticastMessage::NewServer { port: 1234 }
+            ]
+        );
+        assert_eq!(rx_b.recv().await.unwrap().message, MulticastMessage::Join);
+        let Received {
+            message, source, ..
+        } = rx_b.recv().await.unwrap();
+        assert_eq!(message, MulticastMessage::NewServer { port: 1234 });
+        assert_eq!(source.ip(), &Ipv4Addr::LOCALHOST);
+    }
+
+    #[tokio::test]
+    async fn loss_drops_everything() {
+        MemoryCommunicator::set_faults(
+            address(2),
+            Faults {
+                loss: 1.0,
+                ..Default::default()
+            },
+        );
+        let communicator = MemoryCommunicator::try_from_socket_addr(address(2))
+            .await
+            .unwrap();
+        communicator.communicate(b"lost").await.unwrap();
+
+        assert!(communicator.receiver.lock().await.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn duplication_and_reordering() {
+        let communicator = MemoryCommunicator::try_from_socket_addr(address(3))
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+
+        MemoryCommunicator::set_faults(
+            address(3),
+            Faults {
+                duplication: 1.0,
+                ..Default::default()
+            },
+        );
+        communicator.communicate(b"twice").await.unwrap();
+        for _ in 0..2 {
+            let (len, _) = communicator.receive(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"twice");
+        }
+
+        MemoryCommunicator::set_faults(
+            address(3),
+            Faults {
+                reordering: 1.0,
+                ..Default::default()
+            },
+        );
+        communicator.communicate(b"first").await.unwrap();
+        communicator.communicate(b"second").await.unwrap();
+        let (len, _) = communicator.receive(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"second");
+        let (len, _) = communicator.receive(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"first");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_delays_delivery() {
+        MemoryCommunicator::set_faults(
+            address(4),
+            Faults {
+                latency: Duration::from_secs(5),
+                ..Default::default()
+            },
+        );
+        let communicator = MemoryCommunicator::try_from_socket_addr(address(4))
+            .await
+            .unwrap();
+        let start = tokio::time::Instant::now();
+        communicator.communicate(b"late").await.unwrap();
+        communicator.receive(&mut [0; 16]).await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+}
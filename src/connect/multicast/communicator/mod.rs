@@ -1,16 +1,28 @@
 use super::AsyncTryFromSocketAddr;
 use anyhow::Result;
-use std::{fmt::Debug, io};
+use std::{fmt::Debug, io, net::SocketAddrV4};
 
+mod broadcast;
+mod fallback;
 mod ipc;
+mod memory;
 mod socket;
 
+pub use broadcast::BroadcastCommunicator;
+pub use fallback::{FALLBACK_WINDOW, FallbackCommunicator};
 pub use ipc::IpcCommunicator;
+pub use memory::{Faults, MemoryCommunicator};
 pub use socket::SocketCommunicator;
 
-pub trait Communicator: AsyncTryFromSocketAddr + Debug {
-    #[allow(async_fn_in_trait)]
-    async fn communicate(&mut self, bytes: &[u8]) -> Result<usize, io::Error>;
+pub trait Communicator: AsyncTryFromSocketAddr + Debug + Send + Sync + 'static {
+    /// Send `bytes` to the multicast.
+    fn communicate(&self, bytes: &[u8]) -> impl Future<Output = Result<usize, io::Error>> + Send;
+
+    /// Wait for the next datagram on the multicast, returning its length and who sent it.
+    fn receive(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<(usize, SocketAddrV4), io::Error>> + Send;
 }
 
 mod error {
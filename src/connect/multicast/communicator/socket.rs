@@ -1,4 +1,7 @@
-use std::{io, net::SocketAddrV4};
+use std::{
+    io,
+    net::{SocketAddr, SocketAddrV4},
+};
 
 use anyhow::Result;
 use tokio::net::UdpSocket;
@@ -22,7 +25,14 @@ impl super::AsyncTryFromSocketAddr for SocketCommunicator {
 }
 
 impl Communicator for SocketCommunicator {
-    async fn communicate(&mut self, bytes: &[u8]) -> Result<usize, io::Error> {
+    async fn communicate(&self, bytes: &[u8]) -> Result<usize, io::Error> {
         self.socket.send_to(bytes, self.address).await
     }
+
+    async fn receive(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), io::Error> {
+        match self.socket.recv_from(buf).await? {
+            (len, SocketAddr::V4(peer)) => Ok((len, peer)),
+            (_, SocketAddr::V6(_)) => Err(io::Error::other("IPV6 not implemented.")),
+        }
+    }
 }
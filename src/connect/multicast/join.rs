@@ -1,6 +1,7 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 
 use anyhow::{Result, anyhow};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
 use tracing::info;
 
@@ -10,7 +11,12 @@ pub(crate) async fn connect_to_multicast(address: SocketAddrV4) -> Result<UdpSoc
         return Err(anyhow!("Address must be multicast"));
     }
     info!("Joining multicast");
-    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, address.port())).await?;
+    // Reuse the address so that several protocols (or processes) can listen on the same group.
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, address.port()).into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
 
     socket.join_multicast_v4(*address.ip(), std::net::Ipv4Addr::UNSPECIFIED)?;
 
@@ -0,0 +1,210 @@
+//! Protection against nodes, or anyone able to send datagrams to the multicast, flooding it.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// How many messages a single source may send: `burst` at once, then `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Default for RateLimit {
+    /// Enough for a node to answer every other node of a large network joining at once.
+    fn default() -> Self {
+        Self {
+            burst: 64,
+            per_second: 8.,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket per source, for at most [`MAX_SOURCES`] of them.
+///
+/// Sources should be keyed by IP address alone, as anyone can send from as many ports as they like.
+#[derive(Debug, Clone)]
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    buckets: HashMap<K, Bucket>,
+}
+
+/// How many sources are tracked. Past that, those with a full bucket are forgotten, then those heard the longest ago.
+const MAX_SOURCES: usize = 1024;
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Whether `source` may send another message at `now`, taking a token from its bucket if so.
+    pub fn allow(&mut self, source: &K, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_SOURCES && !self.buckets.contains_key(source) {
+            self.forget_idle(now);
+            forget_oldest(&mut self.buckets, |bucket| bucket.last);
+        }
+        let burst = self.limit.burst as f64;
+        let bucket = self.buckets.entry(source.clone()).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let refill =
+            now.saturating_duration_since(bucket.last).as_secs_f64() * self.limit.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.last = now;
+        if bucket.tokens < 1. {
+            return false;
+        }
+        bucket.tokens -= 1.;
+
+        true
+    }
+
+    /// Forget the sources whose bucket would be full by `now`, which are as good as new.
+    fn forget_idle(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, bucket| {
+            let refill =
+                now.saturating_duration_since(bucket.last).as_secs_f64() * limit.per_second;
+            bucket.tokens + refill < limit.burst as f64
+        });
+    }
+}
+
+/// Forget the entry of `map` heard the longest ago if it's still full.
+fn forget_oldest<K: Eq + Hash + Clone, V>(map: &mut HashMap<K, V>, heard: impl Fn(&V) -> Instant) {
+    if map.len() < MAX_SOURCES {
+        return;
+    }
+    let oldest = map
+        .iter()
+        .min_by_key(|(_, value)| heard(value))
+        .map(|(key, _)| key.clone());
+    if let Some(oldest) = oldest {
+        map.remove(&oldest);
+    }
+}
+
+/// Remembers the last message of each kind from each source for a while, to tell repeats apart, for at most
+/// [`MAX_SOURCES`] of them.
+#[derive(Debug, Clone)]
+pub struct Dedup<K, M> {
+    window: Duration,
+    last: HashMap<K, (M, Instant)>,
+}
+
+impl<K: Eq + Hash + Clone, M: PartialEq> Dedup<K, M> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Whether `message` is the same as the last one under `key`, received less than the window before `now`.
+    /// Either way, it becomes the last one.
+    pub fn is_repeat(&mut self, key: K, message: M, now: Instant) -> bool {
+        if self.last.len() >= MAX_SOURCES && !self.last.contains_key(&key) {
+            let window = self.window;
+            self.last
+                .retain(|_, (_, at)| now.saturating_duration_since(*at) < window);
+            forget_oldest(&mut self.last, |(_, at)| *at);
+        }
+        let repeat = self.last.get(&key).is_some_and(|(last, at)| {
+            *last == message && now.saturating_duration_since(*at) < self.window
+        });
+        if !repeat {
+            self.last.insert(key, (message, now));
+        }
+
+        repeat
+    }
+
+    /// Forget what was received under the keys `matches` picks.
+    pub fn forget(&mut self, mut matches: impl FnMut(&K) -> bool) {
+        self.last.retain(|key, _| !matches(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    #[test]
+    fn limits_each_source_to_its_rate() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            burst: 3,
+            per_second: 2.,
+        });
+        let start = Instant::now();
+        let allowed = (0..10).filter(|_| limiter.allow(&"flood", start)).count();
+        assert_eq!(allowed, 3);
+        assert!(limiter.allow(&"other", start));
+
+        assert!(!limiter.allow(&"flood", start + Duration::from_millis(400)));
+        assert!(limiter.allow(&"flood", start + Duration::from_millis(500)));
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            (0..10).filter(|_| limiter.allow(&"flood", later)).count(),
+            3
+        );
+    }
+
+    #[test]
+    fn rotating_ports_share_a_bucket() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            burst: 3,
+            per_second: 2.,
+        });
+        let start = Instant::now();
+        let flood = (0..10_000u16).map(|port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 66), port));
+        let allowed = flood
+            .filter(|source| limiter.allow(source.ip(), start))
+            .count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn sources_are_capped() {
+        let mut limiter = RateLimiter::new(RateLimit::default());
+        let mut dedup = Dedup::new(Duration::from_secs(5));
+        let start = Instant::now();
+        for i in 0..MAX_SOURCES as u32 * 2 {
+            let source = Ipv4Addr::from(i);
+            assert!(limiter.allow(&source, start));
+            assert!(!dedup.is_repeat(source, (), start));
+        }
+        assert_eq!(limiter.buckets.len(), MAX_SOURCES);
+        assert_eq!(dedup.last.len(), MAX_SOURCES);
+    }
+
+    #[test]
+    fn only_repeats_within_the_window_are_dropped() {
+        let mut dedup = Dedup::new(Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(!dedup.is_repeat(1, "a", start));
+        assert!(dedup.is_repeat(1, "a", start + Duration::from_secs(1)));
+        assert!(!dedup.is_repeat(2, "a", start));
+        // A change and back again isn't a repeat.
+        assert!(!dedup.is_repeat(1, "b", start + Duration::from_secs(2)));
+        assert!(!dedup.is_repeat(1, "a", start + Duration::from_secs(3)));
+        assert!(!dedup.is_repeat(1, "a", start + Duration::from_secs(9)));
+
+        dedup.forget(|key| *key == 1);
+        assert!(!dedup.is_repeat(1, "a", start + Duration::from_secs(10)));
+    }
+}
@@ -1,25 +1,187 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    net::{SocketAddr, SocketAddrV4},
+};
 
+use anyhow::Result;
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Decode, Encode)]
+use crate::identity::Signer;
+
+#[derive(Debug, Serialize, Deserialize, Decode, Encode, PartialEq, Eq, Clone)]
 pub enum MulticastMessage {
     Join,
-    NewServer { port: u16 },
-    CloseServer { port: u16 },
+    NewServer {
+        port: u16,
+    },
+    CloseServer {
+        port: u16,
+    },
+    /// The channels the node listening on `port` is in.
+    Channels {
+        port: u16,
+        channels: Vec<String>,
+    },
+    /// Everything needed to reach a node, sent along with its [`NewServer`](Self::NewServer) for the nodes which
+    /// can't make do with the address the announcement came from.
+    Announce {
+        /// The address the node knows itself by.
+        id: SocketAddrV4,
+        name: String,
+        /// Where the node may be reached, the preferred first.
+        endpoints: Vec<SocketAddr>,
+        /// What the node supports, for other nodes to know what they may ask of it.
+        features: Vec<String>,
+    },
 }
 
-pub trait Message: Debug + Encode {
+/// First bytes of every datagram sent by a [`MulticastServer`](super::server::MulticastServer), so that other traffic
+/// on the group is told apart without decoding it.
+pub const MAGIC: [u8; 4] = *b"CHAT";
+
+/// Version of the layout of the [`Header`], which follows the [`MAGIC`] and this version. Datagrams of other
+/// versions are ignored.
+pub const VERSION: u8 = 1;
+
+/// A protocol that can be spoken over the multicast by a [`MulticastServer`](super::server::MulticastServer).
+///
+/// Several protocols may share the same multicast group: every datagram is prefixed with a [`Header`] naming the
+/// [`TOPIC`](Message::TOPIC) it belongs to, and servers silently ignore datagrams addressed to other topics.
+///
+/// New kinds of messages may be added to a protocol: older nodes ignore those of a [`kind`](Message::kind) they
+/// don't [know](Message::is_known_kind). Existing kinds must keep their number and encoding.
+pub trait Message: Debug + Encode + Decode<()> + Send + 'static {
+    /// Namespace of this protocol on the multicast group. Must be unique among the protocols sharing a group.
+    const TOPIC: &'static str;
+
+    /// The message sent when first joining the multicast.
     fn join() -> Self;
+
+    /// Tag identifying which kind of message this is, so receivers can tell messages apart without decoding them.
+    fn kind(&self) -> u8;
+
+    /// Whether this version of the protocol has messages of `kind`.
+    fn is_known_kind(kind: u8) -> bool;
+
+    /// What older nodes meant by a legacy `HI` datagram announcing their server on `port`, if this protocol has an
+    /// equivalent. See [`parse_hi`].
+    fn from_legacy_hi(_port: u16) -> Option<Self> {
+        None
+    }
+}
+
+/// The port announced by a legacy `HI` datagram: `HI` followed by the port, big-endian, from before messages were
+/// encoded with a [`Header`].
+pub fn parse_hi(bytes: &[u8]) -> Option<u16> {
+    match bytes {
+        [b'H', b'I', port @ ..] => Some(u16::from_be_bytes(port.try_into().ok()?)),
+        _ => None,
+    }
 }
 
 impl Message for () {
+    const TOPIC: &'static str = "()";
+
     fn join() -> Self {}
+
+    fn kind(&self) -> u8 {
+        0
+    }
+
+    fn is_known_kind(kind: u8) -> bool {
+        kind == 0
+    }
 }
 
 impl Message for MulticastMessage {
+    const TOPIC: &'static str = "chat-async";
+
     fn join() -> Self {
         Self::Join
     }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Self::Join => 0,
+            Self::NewServer { .. } => 1,
+            Self::CloseServer { .. } => 2,
+            Self::Channels { .. } => 3,
+            Self::Announce { .. } => 4,
+        }
+    }
+
+    fn is_known_kind(kind: u8) -> bool {
+        kind <= 4
+    }
+
+    fn from_legacy_hi(port: u16) -> Option<Self> {
+        Some(Self::NewServer { port })
+    }
+}
+
+/// Prefix of every datagram sent by a [`MulticastServer`](super::server::MulticastServer), after the [`MAGIC`] and
+/// [`VERSION`].
+#[derive(Debug, Decode, Encode, PartialEq, Eq)]
+pub struct Header {
+    pub topic: String,
+    pub kind: u8,
+    /// Picked at random by each server, to tell the datagrams of different servers on the same address apart.
+    pub sender: u64,
+    pub signature: Option<Signature>,
+    /// HMAC of everything else in the datagram with the [`RoomKey`](crate::room::RoomKey) of the sender's room.
+    pub room_mac: Option<[u8; 32]>,
+}
+
+impl Header {
+    pub fn new<M: Message>(msg: &M, sender: u64) -> Self {
+        Self {
+            topic: M::TOPIC.to_owned(),
+            kind: msg.kind(),
+            sender,
+            signature: None,
+            room_mac: None,
+        }
+    }
+
+    /// The bytes of the header covered by [`room_mac`](Self::room_mac), which is followed by the encoded message.
+    pub fn mac_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(
+            (
+                VERSION,
+                &self.topic,
+                self.kind,
+                self.sender,
+                &self.signature,
+            ),
+            bincode::config::standard(),
+        )?)
+    }
+
+    /// The bytes covered by [`Signature::signature`]: everything in the header but the signature itself, and the
+    /// encoded message.
+    pub fn signed_bytes(&self, signer: &Signer, timestamp: u64, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut bytes = bincode::encode_to_vec(
+            (
+                VERSION,
+                &self.topic,
+                self.kind,
+                self.sender,
+                signer,
+                timestamp,
+            ),
+            bincode::config::standard(),
+        )?;
+        bytes.extend_from_slice(payload);
+
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Decode, Encode, PartialEq, Eq, Clone)]
+pub struct Signature {
+    pub signer: Signer,
+    /// Milliseconds since the Unix epoch when the message was signed, so that old messages can't be replayed.
+    pub timestamp: u64,
+    pub signature: [u8; 64],
 }
@@ -3,6 +3,7 @@ use std::net::SocketAddrV4;
 
 pub mod communicator;
 pub mod join;
+pub mod limit;
 pub mod message;
 pub mod server;
 
@@ -10,6 +11,5 @@ pub trait AsyncTryFromSocketAddr
 where
     Self: Sized,
 {
-    #[allow(async_fn_in_trait)]
-    async fn try_from_socket_addr(addr: SocketAddrV4) -> Result<Self>;
+    fn try_from_socket_addr(addr: SocketAddrV4) -> impl Future<Output = Result<Self>> + Send;
 }
@@ -1,46 +1,329 @@
-use std::net::SocketAddrV4;
+use std::{
+    net::SocketAddrV4,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
-use tokio::sync::mpsc::Sender;
+use anyhow::{Result, anyhow};
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use tracing::{info, trace, warn};
 
-use crate::connect::multicast::communicator::{Communicator, SocketCommunicator};
+use crate::{
+    connect::multicast::communicator::{Communicator, SocketCommunicator},
+    identity::{Identity, KeyStore, Signer},
+    room::RoomKey,
+};
 
-use super::message::Message;
+use super::{
+    limit::{RateLimit, RateLimiter},
+    message::{Header, MAGIC, Message, Signature, VERSION, parse_hi},
+};
+
+/// How far the timestamp of a signed message may be from our clock before it is considered a replay.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// A message decoded from a datagram.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Decoded<M: Message> {
+    pub message: M,
+    /// The [`sender`](Header::sender) of the datagram.
+    pub sender: u64,
+    /// Who signed the message, if it was signed.
+    pub signer: Option<Signer>,
+}
+
+/// A message received from the multicast, along with the address that sent it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Received<M: Message> {
+    pub message: M,
+    pub source: SocketAddrV4,
+    /// Who signed the message, if it was signed.
+    pub signer: Option<Signer>,
+    /// The [`sender`](Header::sender) of the datagram, or 0 for a legacy one.
+    pub sender: u64,
+    /// Whether the message came in the legacy `HI` format, from a node that doesn't speak this protocol yet.
+    pub legacy: bool,
+}
+
+/// How a [`MulticastServer`] signs its messages and checks the signatures of others'.
+#[derive(Debug, Clone, Default)]
+pub struct Security {
+    /// Name and identity to sign messages with. Messages are sent unsigned if [`None`].
+    pub identity: Option<(String, Identity)>,
+    /// Keys each signer's name was first seen with.
+    pub keys: KeyStore,
+    /// Drop messages that are unsigned, badly signed, or signed with a key other than the one their signer's name
+    /// was first seen with. Otherwise, those are only logged, and bad signatures are treated as no signature.
+    pub strict: bool,
+    /// Private room to authenticate messages for. Messages from other rooms, or from outside any room if this is set,
+    /// are ignored.
+    pub room: Option<RoomKey>,
+    /// How many datagrams each source address may send. Those over the limit are dropped before being decoded.
+    pub rate_limit: RateLimit,
+    /// Also take legacy `HI` datagrams, as unsigned messages, while older nodes are still around. As they can't be
+    /// authenticated, they're ignored in [`strict`](Self::strict) mode or in a [`room`](Self::room) anyway.
+    pub legacy_hi: bool,
+}
 
 /// Handles communication with the multicast and mantains an updated list with all of the open servers.
 #[derive(Debug)]
 pub struct MulticastServer<M: Message, C: Communicator = SocketCommunicator> {
-    communicator: C,
+    communicator: Arc<C>,
+    /// The [`sender`](Header::sender) of every datagram of this server.
+    id: u64,
     buf: [u8; 4096],
-    sender: Sender<M>,
+    security: Security,
+    receiver: JoinHandle<Result<()>>,
+    _message: std::marker::PhantomData<M>,
 }
 
 impl<M: Message, C: Communicator> MulticastServer<M, C> {
     #[tracing::instrument(name = "MulticastServer::send", skip(self))]
     pub async fn send(&mut self, msg: M) -> Result<()> {
-        let encoded = Self::encode(&mut self.buf, msg)?;
+        let encoded = Self::encode(&mut self.buf, msg, self.id, &self.security)?;
         self.communicator.communicate(encoded).await?;
 
         Ok(())
     }
 
-    #[tracing::instrument]
-    fn encode(buf: &mut [u8], msg: M) -> Result<&[u8]> {
-        let len = bincode::encode_into_slice(msg, buf, bincode::config::standard())?;
+    /// Encode `msg` into `buf`: the [`MAGIC`], the [`VERSION`], the [`Header`] and the message itself, signed and
+    /// authenticated for a room as configured by `security`.
+    #[tracing::instrument(skip(buf, security))]
+    pub fn encode<'a>(
+        buf: &'a mut [u8],
+        msg: M,
+        sender: u64,
+        security: &Security,
+    ) -> Result<&'a [u8]> {
+        let mut header = Header::new(&msg, sender);
+        let payload = bincode::encode_to_vec(msg, bincode::config::standard())?;
+        if let Some((name, identity)) = &security.identity {
+            let signer = Signer {
+                name: name.clone(),
+                key: identity.public_key(),
+            };
+            let timestamp = now_millis();
+            let signature = identity.sign(&header.signed_bytes(&signer, timestamp, &payload)?);
+            header.signature = Some(Signature {
+                signer,
+                timestamp,
+                signature,
+            });
+        }
+        if let Some(room) = &security.room {
+            header.room_mac = Some(room.mac(&[&header.mac_bytes()?, &payload]));
+        }
+
+        let prefix_len = MAGIC.len() + 1;
+        if buf.len() < prefix_len {
+            return Err(anyhow!("Buffer of {} bytes is too small", buf.len()));
+        }
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        buf[MAGIC.len()] = VERSION;
+        let header_len = prefix_len
+            + bincode::encode_into_slice(
+                header,
+                &mut buf[prefix_len..],
+                bincode::config::standard(),
+            )?;
+        let len = header_len + payload.len();
+        if len > buf.len() {
+            return Err(anyhow!("Message of {len} bytes is too big"));
+        }
+        buf[header_len..len].copy_from_slice(&payload);
 
         Ok(&buf[..len])
     }
+
+    /// Decode a datagram, returning [`None`] if it isn't meant for this server: if it's some other traffic, from
+    /// another version, topic or room, or a kind of message this version of the protocol doesn't know.
+    pub fn decode(bytes: &[u8], security: &Security) -> Result<Option<Decoded<M>>> {
+        let Some(rest) = bytes.strip_prefix(&MAGIC) else {
+            trace!("Ignoring datagram without the magic bytes.");
+            return Ok(None);
+        };
+        let Some((&version, rest)) = rest.split_first() else {
+            return Ok(None);
+        };
+        if version != VERSION {
+            trace!("Ignoring datagram of version {version}.");
+            return Ok(None);
+        }
+        let (header, header_len): (Header, _) =
+            bincode::decode_from_slice(rest, bincode::config::standard())?;
+        if header.topic != M::TOPIC {
+            return Ok(None);
+        }
+        let payload = &rest[header_len..];
+        match (&security.room, &header.room_mac) {
+            (None, None) => {}
+            (Some(room), Some(mac)) if room.verify(&[&header.mac_bytes()?, payload], mac) => {}
+            _ => return Ok(None),
+        }
+        let signer = Self::authenticate(&header, payload, security)?;
+        let message = match bincode::decode_from_slice(payload, bincode::config::standard()) {
+            Ok((message, _)) => message,
+            Err(_) if !M::is_known_kind(header.kind) => {
+                trace!("Ignoring message of unknown kind {}.", header.kind);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(Decoded {
+            message,
+            sender: header.sender,
+            signer,
+        }))
+    }
+
+    /// Decode a legacy `HI` datagram, if `security` lets them in and the protocol has an equivalent.
+    fn decode_legacy(bytes: &[u8], security: &Security) -> Option<M> {
+        if !security.legacy_hi || security.strict || security.room.is_some() {
+            return None;
+        }
+        parse_hi(bytes).and_then(M::from_legacy_hi)
+    }
+
+    /// Check the signature of a message, as configured by `security`.
+    fn authenticate(
+        header: &Header,
+        payload: &[u8],
+        security: &Security,
+    ) -> Result<Option<Signer>> {
+        let Some(Signature {
+            signer,
+            timestamp,
+            signature,
+        }) = &header.signature
+        else {
+            if security.strict {
+                return Err(anyhow!("Message isn't signed"));
+            }
+            return Ok(None);
+        };
+
+        let verified = if now_millis().abs_diff(*timestamp) > MAX_CLOCK_SKEW.as_millis() as u64 {
+            Err(anyhow!("Signature timestamp is too far from our clock"))
+        } else {
+            signer.key.verify(
+                &header.signed_bytes(signer, *timestamp, payload)?,
+                signature,
+            )
+        };
+        if let Err(e) = verified {
+            if security.strict {
+                return Err(e);
+            }
+            warn!("Ignoring bad signature from {}: {e}", signer.name);
+            return Ok(None);
+        }
+
+        if let Err(e) = security.keys.check(signer) {
+            if security.strict {
+                return Err(e.into());
+            }
+            warn!("{e}");
+        }
+
+        Ok(Some(signer.clone()))
+    }
+
+    /// Read from the multicast until `sender` is closed, forwarding every message of this server's topic.
+    #[tracing::instrument(name = "MulticastServer::receive", skip_all, fields(topic = M::TOPIC))]
+    async fn receive(
+        communicator: Arc<C>,
+        sender: Sender<Received<M>>,
+        security: Security,
+    ) -> Result<()> {
+        let mut buf = [0; 4096];
+        let mut limiter = RateLimiter::new(security.rate_limit);
+        loop {
+            let (len, source) = tokio::select! {
+                res = communicator.receive(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Error receiving from multicast: {e}");
+                        continue;
+                    }
+                },
+                () = sender.closed() => {
+                    info!("Message channel was closed. Will stop receiving from multicast.");
+                    break;
+                }
+            };
+            if !limiter.allow(source.ip(), Instant::now()) {
+                trace!("Dropping message from {source}, which sends too many.");
+                continue;
+            }
+            let decoded = match Self::decode_legacy(&buf[..len], &security) {
+                Some(message) => Ok(Some((
+                    Decoded {
+                        message,
+                        sender: 0,
+                        signer: None,
+                    },
+                    true,
+                ))),
+                None => Self::decode(&buf[..len], &security)
+                    .map(|decoded| decoded.map(|decoded| (decoded, false))),
+            };
+            match decoded {
+                Ok(Some((
+                    Decoded {
+                        message,
+                        sender: id,
+                        signer,
+                    },
+                    legacy,
+                ))) => {
+                    let received = Received {
+                        message,
+                        source,
+                        signer,
+                        sender: id,
+                        legacy,
+                    };
+                    if sender.send(received).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => trace!("Ignoring message from another topic or room."),
+                Err(e) => info!("Received invalid message from {source}: {e}"),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<M: Message, C: Communicator> MulticastServer<M, C> {
-    #[tracing::instrument(skip(msg_sender))]
-    pub async fn join(address: SocketAddrV4, msg_sender: Sender<M>) -> Result<Self> {
+    /// Join the multicast at `address`. Every message received on it will be sent through `msg_sender`.
+    pub async fn join(address: SocketAddrV4, msg_sender: Sender<Received<M>>) -> Result<Self> {
+        Self::join_with(address, msg_sender, Security::default()).await
+    }
+
+    /// Like [`join`](Self::join), signing and checking messages as configured by `security`.
+    #[tracing::instrument(skip(msg_sender, security))]
+    pub async fn join_with(
+        address: SocketAddrV4,
+        msg_sender: Sender<Received<M>>,
+        security: Security,
+    ) -> Result<Self> {
         let buf = [0; 4096];
+        let communicator = Arc::new(C::try_from_socket_addr(address).await?);
 
         let mut server = Self {
-            communicator: C::try_from_socket_addr(address).await?,
+            id: rand::random(),
+            receiver: tokio::spawn(Self::receive(
+                communicator.clone(),
+                msg_sender,
+                security.clone(),
+            )),
+            communicator,
             buf,
-            sender: msg_sender,
+            security,
+            _message: std::marker::PhantomData,
         };
 
         server.send(M::join()).await?;
@@ -48,3 +331,221 @@ impl<M: Message, C: Communicator> MulticastServer<M, C> {
         Ok(server)
     }
 }
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl<M: Message, C: Communicator> Drop for MulticastServer<M, C> {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connect::multicast::{AsyncTryFromSocketAddr, message::MulticastMessage};
+
+    use super::*;
+
+    type Server = MulticastServer<MulticastMessage>;
+
+    const SENDER: u64 = 0x0123_4567_89ab_cdef;
+
+    fn unsigned(message: MulticastMessage) -> Decoded<MulticastMessage> {
+        Decoded {
+            message,
+            sender: SENDER,
+            signer: None,
+        }
+    }
+
+    fn signed_by(name: &str) -> Security {
+        Security {
+            identity: Some((name.to_owned(), Identity::generate())),
+            ..Default::default()
+        }
+    }
+
+    fn strict() -> Security {
+        Security {
+            strict: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let mut buf = [0; 4096];
+        let msg = MulticastMessage::NewServer { port: 4321 };
+        let encoded = Server::encode(&mut buf, msg.clone(), SENDER, &Security::default())
+            .unwrap()
+            .to_vec();
+
+        assert_eq!(
+            Server::decode(&encoded, &Security::default()).unwrap(),
+            Some(unsigned(msg.clone()))
+        );
+    }
+
+    #[test]
+    fn ignores_other_topics() {
+        let mut buf = [0; 4096];
+        let encoded = <MulticastServer<()>>::encode(&mut buf, (), SENDER, &Security::default())
+            .unwrap()
+            .to_vec();
+
+        assert_eq!(
+            Server::decode(&encoded, &Security::default()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let mut buf = [0; 4096];
+        let alice = signed_by("alice");
+        let msg = MulticastMessage::CloseServer { port: 4321 };
+        let encoded = Server::encode(&mut buf, msg.clone(), SENDER, &alice)
+            .unwrap()
+            .to_vec();
+
+        let decoded = Server::decode(&encoded, &strict()).unwrap().unwrap();
+        assert_eq!((decoded.message, decoded.sender), (msg, SENDER));
+        assert_eq!(
+            decoded.signer.unwrap().key,
+            alice.identity.unwrap().1.public_key()
+        );
+
+        // Tampering with the port invalidates the signature.
+        let mut tampered = encoded.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Server::decode(&tampered, &strict()).is_err());
+        assert_eq!(
+            Server::decode(&tampered, &Security::default())
+                .unwrap()
+                .unwrap()
+                .signer,
+            None
+        );
+    }
+
+    #[test]
+    fn strict_rejects_unsigned_and_changed_keys() {
+        let mut buf = [0; 4096];
+        let msg = MulticastMessage::NewServer { port: 4321 };
+        let unsigned = Server::encode(&mut buf, msg.clone(), SENDER, &Security::default())
+            .unwrap()
+            .to_vec();
+        assert!(Server::decode(&unsigned, &strict()).is_err());
+
+        let security = strict();
+        let first = Server::encode(&mut buf, msg.clone(), SENDER, &signed_by("bob"))
+            .unwrap()
+            .to_vec();
+        assert!(Server::decode(&first, &security).is_ok());
+        let impostor = Server::encode(&mut buf, msg, SENDER, &signed_by("bob"))
+            .unwrap()
+            .to_vec();
+        assert!(Server::decode(&impostor, &security).is_err());
+    }
+
+    #[test]
+    fn ignores_other_rooms() {
+        let mut buf = [0; 4096];
+        let msg = MulticastMessage::NewServer { port: 4321 };
+        let in_room = |passphrase: &str| Security {
+            room: Some(RoomKey::from_passphrase(passphrase)),
+            ..Default::default()
+        };
+        let encoded = Server::encode(&mut buf, msg.clone(), SENDER, &in_room("fourth floor"))
+            .unwrap()
+            .to_vec();
+
+        assert_eq!(
+            Server::decode(&encoded, &in_room("fourth floor")).unwrap(),
+            Some(unsigned(msg.clone()))
+        );
+        assert_eq!(
+            Server::decode(&encoded, &in_room("third floor")).unwrap(),
+            None
+        );
+        assert_eq!(
+            Server::decode(&encoded, &Security::default()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn takes_legacy_hi_only_when_asked() {
+        let hi = [b'H', b'I', 0x10, 0xe1];
+        let legacy = Security {
+            legacy_hi: true,
+            ..Default::default()
+        };
+        assert_eq!(Server::decode_legacy(&hi, &Security::default()), None);
+        assert_eq!(
+            Server::decode_legacy(&hi, &legacy),
+            Some(MulticastMessage::NewServer { port: 4321 })
+        );
+        assert_eq!(Server::decode_legacy(&hi[..3], &legacy), None);
+        assert_eq!(Server::decode_legacy(b"HEY!", &legacy), None);
+        let strict = Security {
+            strict: true,
+            ..legacy
+        };
+        assert_eq!(Server::decode_legacy(&hi, &strict), None);
+    }
+
+    /// Fails to receive once, then receives the datagrams it was given.
+    #[derive(Debug)]
+    struct Flaky {
+        failed: std::sync::atomic::AtomicBool,
+        datagrams: std::sync::Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl AsyncTryFromSocketAddr for Flaky {
+        async fn try_from_socket_addr(_: SocketAddrV4) -> Result<Self> {
+            unimplemented!()
+        }
+    }
+
+    impl Communicator for Flaky {
+        async fn communicate(&self, bytes: &[u8]) -> Result<usize, std::io::Error> {
+            Ok(bytes.len())
+        }
+
+        async fn receive(&self, buf: &mut [u8]) -> Result<(usize, SocketAddrV4), std::io::Error> {
+            if !self.failed.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                return Err(std::io::Error::other("IPV6 not implemented."));
+            }
+            let Some(datagram) = self.datagrams.lock().unwrap().pop() else {
+                return std::future::pending().await;
+            };
+            buf[..datagram.len()].copy_from_slice(&datagram);
+            Ok((datagram.len(), "192.0.2.1:4321".parse().unwrap()))
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_receiving_after_an_error() {
+        let mut buf = [0; 4096];
+        let msg = MulticastMessage::NewServer { port: 4321 };
+        let encoded = Server::encode(&mut buf, msg.clone(), SENDER, &Security::default()).unwrap();
+        let flaky = Flaky {
+            failed: Default::default(),
+            datagrams: std::sync::Mutex::new(vec![encoded.to_vec()]),
+        };
+        let (sender, mut received) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(MulticastServer::<MulticastMessage, Flaky>::receive(
+            Arc::new(flaky),
+            sender,
+            Security::default(),
+        ));
+
+        assert_eq!(received.recv().await.unwrap().message, msg);
+    }
+}
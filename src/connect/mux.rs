@@ -0,0 +1,439 @@
+//! Several streams of frames over a single link, so that a big file transfer doesn't hold up chat lines.
+//!
+//! Every [`Frame`] goes on one of a few [`Stream`]s, each with its own queue and its own flow control window, like in
+//! yamux: a stream may only have [`WINDOW`] bytes in flight, and the receiver grants more with a
+//! [`WindowUpdate`](MuxFrame::WindowUpdate) once it has handled them. Queued frames are sent by order of priority,
+//! the most urgent streams first.
+//!
+//! What happens when a queue is full because the other side stopped reading is up to the [`QueuePolicy`].
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+use thiserror::Error;
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+use tracing::warn;
+
+use crate::chat::Frame;
+
+/// How many bytes of a stream may be in flight before the receiver grants more.
+pub const WINDOW: u32 = 256 << 10;
+
+/// How many frames of a stream may wait to be sent, unless the [`QueuePolicy`] says otherwise.
+pub const QUEUE_LEN: usize = 256;
+
+/// What to do with a frame when the queue of its stream is full, for every stream but [`Stream::Files`], on which
+/// the sender always waits so that no part of a file is lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Make room by dropping the oldest frame in the queue.
+    DropOldest,
+    /// Close the link once `threshold` frames are waiting, counting on the link being opened again and history
+    /// being synced once the peer reads again.
+    Disconnect { threshold: usize },
+    /// Wait up to `timeout` for room, then drop the frame.
+    Block { timeout: Duration },
+}
+
+impl QueuePolicy {
+    fn queue_len(&self) -> usize {
+        match self {
+            QueuePolicy::Disconnect { threshold } => *threshold,
+            _ => QUEUE_LEN,
+        }
+    }
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        QueuePolicy::Disconnect {
+            threshold: QUEUE_LEN,
+        }
+    }
+}
+
+/// How full the queues of a link are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Frames waiting to be sent, over every stream.
+    pub queued: usize,
+    /// The most frames that were ever waiting at once.
+    pub peak: usize,
+    /// Frames dropped because their queue was full.
+    pub dropped: u64,
+}
+
+/// The streams of a link, from the most urgent to the least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum Stream {
+    Control,
+    Chat,
+    History,
+    Files,
+}
+
+impl Stream {
+    const ALL: [Stream; 4] = [
+        Stream::Control,
+        Stream::Chat,
+        Stream::History,
+        Stream::Files,
+    ];
+
+    /// The stream `frame` is sent on.
+    pub fn of(frame: &Frame) -> Self {
+        match frame {
+            Frame::Chat(_) | Frame::Private(_) | Frame::Ack { .. } => Stream::Chat,
+            Frame::Summary(_) | Frame::History(_) => Stream::History,
+            Frame::Chunk { .. } => Stream::Files,
+            _ => Stream::Control,
+        }
+    }
+}
+
+/// What goes on the wire once a link is up.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum MuxFrame {
+    /// An encoded [`Frame`].
+    Data { stream: Stream, payload: Vec<u8> },
+    /// Lets the other side send `credit` more bytes on `stream`.
+    WindowUpdate { stream: Stream, credit: u32 },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QueueError {
+    #[error("link closed")]
+    Closed,
+    #[error("queue full, frame dropped")]
+    Full,
+}
+
+#[derive(Debug)]
+struct State {
+    queues: [VecDeque<Vec<u8>>; 4],
+    /// How many bytes of each stream may still be sent. Goes below zero when a frame bigger than what's left is sent.
+    credit: [i64; 4],
+    /// How many bytes of each stream were handled but not granted back yet.
+    consumed: [u32; 4],
+    updates: VecDeque<MuxFrame>,
+    closed: bool,
+    peak: usize,
+    dropped: u64,
+}
+
+impl State {
+    fn push(&mut self, stream: Stream, payload: Vec<u8>) {
+        self.queues[stream as usize].push_back(payload);
+        let queued = self.queues.iter().map(VecDeque::len).sum();
+        self.peak = self.peak.max(queued);
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    policy: QueuePolicy,
+    state: Mutex<State>,
+    /// Space left in each queue.
+    space: [Semaphore; 4],
+    /// Wakes the writer.
+    ready: Notify,
+}
+
+/// Handle to both directions of a multiplexed link.
+#[derive(Debug, Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+}
+
+impl Mux {
+    pub fn new(policy: QueuePolicy) -> Self {
+        let queue = || Semaphore::new(policy.queue_len());
+        Self {
+            shared: Arc::new(Shared {
+                policy,
+                state: Mutex::new(State {
+                    queues: Default::default(),
+                    credit: [WINDOW as i64; 4],
+                    consumed: [0; 4],
+                    updates: VecDeque::new(),
+                    closed: false,
+                    peak: 0,
+                    dropped: 0,
+                }),
+                space: [queue(), queue(), queue(), queue()],
+                ready: Notify::new(),
+            }),
+        }
+    }
+
+    /// Queue `frame` on its [`Stream`], doing what the [`QueuePolicy`] says if the queue is full.
+    pub async fn send(&self, frame: Frame) -> Result<(), QueueError> {
+        let stream = Stream::of(&frame);
+        let payload = bincode::encode_to_vec(&frame, bincode::config::standard())
+            .expect("frames can always be encoded");
+        let space = &self.shared.space[stream as usize];
+        let permit = match space.try_acquire() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::Closed) => return Err(QueueError::Closed),
+            Err(TryAcquireError::NoPermits) => match self.shared.policy {
+                _ if stream == Stream::Files => {
+                    space.acquire().await.map_err(|_| QueueError::Closed)?
+                }
+                QueuePolicy::DropOldest => {
+                    {
+                        let mut state = self.shared.state.lock().unwrap();
+                        if state.closed {
+                            return Err(QueueError::Closed);
+                        }
+                        // Takes the place of the frame dropped, if any. Otherwise, the queue is only full of frames
+                        // about to be pushed, so this one can wait for them.
+                        if state.queues[stream as usize].pop_front().is_some() {
+                            state.dropped += 1;
+                            state.push(stream, payload);
+                            return Ok(());
+                        }
+                    }
+                    space.acquire().await.map_err(|_| QueueError::Closed)?
+                }
+                QueuePolicy::Disconnect { threshold } => {
+                    warn!(
+                        "{threshold} frames waiting for a peer that stopped reading. Closing the link."
+                    );
+                    self.close();
+                    return Err(QueueError::Full);
+                }
+                QueuePolicy::Block { timeout } => {
+                    match tokio::time::timeout(timeout, space.acquire()).await {
+                        Ok(permit) => permit.map_err(|_| QueueError::Closed)?,
+                        Err(_) => {
+                            self.shared.state.lock().unwrap().dropped += 1;
+                            return Err(QueueError::Full);
+                        }
+                    }
+                }
+            },
+        };
+        permit.forget();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(QueueError::Closed);
+        }
+        state.push(stream, payload);
+        drop(state);
+        self.shared.ready.notify_one();
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.shared.state.lock().unwrap();
+        QueueStats {
+            queued: state.queues.iter().map(VecDeque::len).sum(),
+            peak: state.peak,
+            dropped: state.dropped,
+        }
+    }
+
+    /// The next frame to write to the link, once there's one that may be sent, or `None` once the link is closed.
+    pub async fn next_frame(&self) -> Option<MuxFrame> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(update) = state.updates.pop_front() {
+                    return Some(update);
+                }
+                let sendable = Stream::ALL.into_iter().find(|&stream| {
+                    state.credit[stream as usize] > 0 && !state.queues[stream as usize].is_empty()
+                });
+                if let Some(stream) = sendable {
+                    let payload = state.queues[stream as usize].pop_front().unwrap();
+                    state.credit[stream as usize] -= payload.len() as i64;
+                    self.shared.space[stream as usize].add_permits(1);
+                    return Some(MuxFrame::Data { stream, payload });
+                }
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    /// Take in a frame read from the link, returning the [`Frame`] it carries, if any. Once that frame is handled,
+    /// [`consumed`](Self::consumed) must be called with the stream and length of the payload.
+    pub fn receive(&self, frame: MuxFrame) -> Result<Option<(Stream, usize, Frame)>> {
+        match frame {
+            MuxFrame::Data { stream, payload } => {
+                let (frame, _) = bincode::decode_from_slice(&payload, bincode::config::standard())?;
+                Ok(Some((stream, payload.len(), frame)))
+            }
+            MuxFrame::WindowUpdate { stream, credit } => {
+                self.shared.state.lock().unwrap().credit[stream as usize] += credit as i64;
+                self.shared.ready.notify_one();
+                Ok(None)
+            }
+        }
+    }
+
+    /// Count `len` bytes of `stream` as handled, granting them back to the other side once they add up to half a
+    /// window.
+    pub fn consumed(&self, stream: Stream, len: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        let consumed = &mut state.consumed[stream as usize];
+        *consumed = consumed.saturating_add(len as u32);
+        if *consumed >= WINDOW / 2 {
+            let credit = std::mem::take(consumed);
+            state
+                .updates
+                .push_back(MuxFrame::WindowUpdate { stream, credit });
+            drop(state);
+            self.shared.ready.notify_one();
+        }
+    }
+
+    /// Stop sending, dropping whatever is still queued.
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        for space in &self.shared.space {
+            space.close();
+        }
+        self.shared.ready.notify_one();
+    }
+}
+
+impl Default for Mux {
+    fn default() -> Self {
+        Self::new(QueuePolicy::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::chat::{ChatMessage, DEFAULT_CHANNEL, MessageId};
+    use crate::{connect::causal::VectorClock, identity::Identity};
+
+    use super::*;
+
+    /// Poll `future` once.
+    fn now_or_never<T>(future: impl Future<Output = T>) -> Option<T> {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(value) => Some(value),
+            Poll::Pending => None,
+        }
+    }
+
+    fn chunk(offset: u64) -> Frame {
+        Frame::Chunk {
+            id: 0,
+            offset,
+            data: vec![0; 100 << 10],
+        }
+    }
+
+    fn stream_of(frame: Option<MuxFrame>) -> Stream {
+        match frame {
+            Some(MuxFrame::Data { stream, .. }) => stream,
+            frame => panic!("expected data, got {frame:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_overtakes_files_and_windows_hold_files_back() {
+        let (sender, receiver) = (Mux::default(), Mux::default());
+        for i in 0..4 {
+            sender.send(chunk(i)).await.unwrap();
+        }
+        sender
+            .send(Frame::Chat(ChatMessage {
+                id: MessageId {
+                    origin: Identity::generate().public_key(),
+                    seq: 1,
+                },
+                ttl: 1,
+                deps: VectorClock::default(),
+                channel: DEFAULT_CHANNEL.to_owned(),
+                author: "alice".to_owned(),
+                text: "still there?".to_owned(),
+            }))
+            .await
+            .unwrap();
+
+        assert_eq!(stream_of(sender.next_frame().await), Stream::Chat);
+        // 100 KiB chunks: the third one goes over the 256 KiB window, and the fourth has to wait.
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            let frame = sender.next_frame().await;
+            assert_eq!(stream_of(frame.clone()), Stream::Files);
+            sent.push(frame.unwrap());
+        }
+        assert_eq!(now_or_never(sender.next_frame()), None);
+
+        // Once the receiver has handled enough of them, it grants more.
+        for frame in sent {
+            let (stream, len, _) = receiver.receive(frame).unwrap().unwrap();
+            receiver.consumed(stream, len);
+        }
+        let update = receiver.next_frame().await.unwrap();
+        assert!(matches!(
+            update,
+            MuxFrame::WindowUpdate {
+                stream: Stream::Files,
+                ..
+            }
+        ));
+        assert!(sender.receive(update).unwrap().is_none());
+        assert_eq!(stream_of(sender.next_frame().await), Stream::Files);
+
+        sender.close();
+        assert_eq!(sender.next_frame().await, None);
+        assert_eq!(sender.send(chunk(4)).await, Err(QueueError::Closed));
+    }
+
+    fn line(text: &str) -> Frame {
+        Frame::Subscriptions(vec![text.to_owned()])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queues_follow_the_policy() {
+        let dropping = Mux::new(QueuePolicy::DropOldest);
+        for i in 0..QUEUE_LEN + 2 {
+            dropping.send(line(&i.to_string())).await.unwrap();
+        }
+        let stats = dropping.stats();
+        assert_eq!(
+            (stats.queued, stats.peak, stats.dropped),
+            (QUEUE_LEN, QUEUE_LEN, 2)
+        );
+        let Some(MuxFrame::Data { payload, .. }) = dropping.next_frame().await else {
+            panic!("expected data");
+        };
+        let (oldest, _): (Frame, _) =
+            bincode::decode_from_slice(&payload, bincode::config::standard()).unwrap();
+        assert_eq!(oldest, line("2"));
+
+        let blocking = Mux::new(QueuePolicy::Block {
+            timeout: Duration::from_secs(1),
+        });
+        for i in 0..QUEUE_LEN {
+            blocking.send(line(&i.to_string())).await.unwrap();
+        }
+        assert_eq!(blocking.send(line("late")).await, Err(QueueError::Full));
+        assert_eq!(blocking.stats().dropped, 1);
+
+        let disconnecting = Mux::new(QueuePolicy::Disconnect { threshold: 2 });
+        disconnecting.send(line("a")).await.unwrap();
+        disconnecting.send(line("b")).await.unwrap();
+        assert_eq!(disconnecting.send(line("c")).await, Err(QueueError::Full));
+        assert_eq!(disconnecting.next_frame().await, None);
+    }
+}
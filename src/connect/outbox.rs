@@ -0,0 +1,136 @@
+//! Private messages waiting for their recipient to acknowledge them.
+//!
+//! Messages are queued by the key of the node they're for, sent whenever a link to that node comes up, and only
+//! dropped once acknowledged, so that they survive both the recipient and the sender being offline for a while.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+use crate::{chat::PrivateMessage, identity::PublicKey};
+
+/// Unacknowledged private messages, optionally backed by a file.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    queues: BTreeMap<PublicKey, Vec<PrivateMessage>>,
+}
+
+impl Outbox {
+    /// Open the outbox at `path`, which is created once the first message is queued.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let queues = match std::fs::read(&path) {
+            Ok(bytes) => {
+                bincode::decode_from_slice(&bytes, bincode::config::standard())
+                    .with_context(|| format!("Reading outbox {}", path.display()))?
+                    .0
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            queues,
+        })
+    }
+
+    /// Queue `message` until the node with the key `to` acknowledges it.
+    pub fn push(&mut self, to: PublicKey, message: PrivateMessage) {
+        self.queues.entry(to).or_default().push(message);
+        self.save();
+    }
+
+    /// The messages still waiting for `to`, oldest first.
+    pub fn queued(&self, to: &PublicKey) -> Vec<PrivateMessage> {
+        self.queues.get(to).cloned().unwrap_or_default()
+    }
+
+    /// Drop the message with this `id`, now that `to` acknowledged it. Returns whether it was queued.
+    pub fn acknowledge(&mut self, to: &PublicKey, id: u64) -> bool {
+        let Some(queue) = self.queues.get_mut(to) else {
+            return false;
+        };
+        let len = queue.len();
+        queue.retain(|message| message.id != id);
+        let acknowledged = queue.len() < len;
+        if queue.is_empty() {
+            self.queues.remove(to);
+        }
+        if acknowledged {
+            self.save();
+        }
+
+        acknowledged
+    }
+
+    /// The highest message id queued, if any.
+    pub fn last_id(&self) -> Option<u64> {
+        self.queues
+            .values()
+            .flatten()
+            .map(|message| message.id)
+            .max()
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path
+            && let Err(e) = write(path, &self.queues)
+        {
+            warn!("Couldn't save outbox: {e}");
+        }
+    }
+}
+
+fn write(path: &Path, queues: &BTreeMap<PublicKey, Vec<PrivateMessage>>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Write a copy first, so that a crash can't leave half an outbox behind.
+    let temporary = path.with_extension("tmp");
+    std::fs::write(
+        &temporary,
+        bincode::encode_to_vec(queues, bincode::config::standard())?,
+    )?;
+    std::fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::Identity;
+
+    use super::*;
+
+    #[test]
+    fn keeps_messages_until_acknowledged() {
+        let path = std::env::temp_dir().join(format!("chat-async-outbox-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bob = Identity::generate().public_key();
+        let message = |id| PrivateMessage {
+            id,
+            author: "alice".to_owned(),
+            text: format!("message {id}"),
+        };
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        outbox.push(bob, message(1));
+        outbox.push(bob, message(2));
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.queued(&bob), [message(1), message(2)]);
+        assert_eq!(outbox.last_id(), Some(2));
+        assert!(outbox.acknowledge(&bob, 1));
+        assert!(!outbox.acknowledge(&bob, 1));
+
+        let outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.queued(&bob), [message(2)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
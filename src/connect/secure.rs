@@ -0,0 +1,263 @@
+//! Encrypted and authenticated links between nodes.
+//!
+//! Every TCP stream starts with a `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake. Inside it, each node sends a
+//! [`Proof`]: its name, its [`Identity`]'s public key, and a signature over its Noise static key, tying the encrypted
+//! channel to the node's identity. Once the peer is otherwise admitted, the caller pins the key to the name in the
+//! [`KeyStore`](crate::identity::KeyStore), so that a known peer presenting another key is rejected with
+//! [`TrustError::KeyChanged`](crate::identity::TrustError::KeyChanged).
+//!
+//! Afterwards, frames are sent as `[len: u32][payload]` over a stream of Noise messages, each of which goes on the
+//! wire as `[len: u16][ciphertext]`.
+
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode};
+use snow::{StatelessTransportState, params::NoiseParams};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    connect::frame::MAX_FRAME_LEN,
+    identity::{Identity, Signer},
+};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, and the authentication tag in each of them.
+const MAX_NOISE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// Prefix of the bytes a [`Proof`] signs, so that the signature can't be reused for anything else.
+const PROOF_CONTEXT: &[u8] = b"chat-async noise static key";
+
+/// Sent by each node during the handshake to prove which identity is behind its Noise static key.
+#[derive(Debug, Encode, Decode)]
+struct Proof {
+    signer: Signer,
+    signature: [u8; 64],
+}
+
+impl Proof {
+    fn new(name: &str, identity: &Identity, static_key: &[u8]) -> Self {
+        Self {
+            signer: Signer {
+                name: name.to_owned(),
+                key: identity.public_key(),
+            },
+            signature: identity.sign(&[PROOF_CONTEXT, static_key].concat()),
+        }
+    }
+
+    fn verify(&self, static_key: &[u8]) -> Result<()> {
+        self.signer
+            .key
+            .verify(&[PROOF_CONTEXT, static_key].concat(), &self.signature)
+    }
+}
+
+/// Perform the handshake over the halves of a stream, returning their encrypted versions and who is on the other side.
+///
+/// `initiator` must be set by the end that opened the connection. The signer is proven to hold its key, but isn't
+/// checked against the keys known for its name.
+pub async fn handshake<R, W>(
+    (mut reader, mut writer): (R, W),
+    initiator: bool,
+    name: &str,
+    identity: &Identity,
+) -> Result<(SecureReader<R>, SecureWriter<W>, Signer)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let params: NoiseParams = NOISE_PARAMS.parse()?;
+    let builder = snow::Builder::new(params);
+    let static_key = builder.generate_keypair()?;
+    let builder = builder.local_private_key(&static_key.private);
+    let mut noise = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+    let proof = bincode::encode_to_vec(
+        Proof::new(name, identity, &static_key.public),
+        bincode::config::standard(),
+    )?;
+    let mut message = vec![0; MAX_NOISE_LEN];
+    let mut payload = vec![0; MAX_NOISE_LEN];
+
+    // -> e
+    // <- e, ee, s, es, proof
+    // -> s, se, proof
+    let remote_proof = if initiator {
+        let len = noise.write_message(&[], &mut message)?;
+        write_noise(&mut writer, &message[..len]).await?;
+        let len = noise.read_message(&read_noise(&mut reader).await?, &mut payload)?;
+        let remote_proof = payload[..len].to_vec();
+        let len = noise.write_message(&proof, &mut message)?;
+        write_noise(&mut writer, &message[..len]).await?;
+        remote_proof
+    } else {
+        noise.read_message(&read_noise(&mut reader).await?, &mut payload)?;
+        let len = noise.write_message(&proof, &mut message)?;
+        write_noise(&mut writer, &message[..len]).await?;
+        let len = noise.read_message(&read_noise(&mut reader).await?, &mut payload)?;
+        payload[..len].to_vec()
+    };
+
+    let (remote_proof, _): (Proof, _) =
+        bincode::decode_from_slice(&remote_proof, bincode::config::standard())?;
+    let remote_static = noise
+        .get_remote_static()
+        .ok_or_else(|| anyhow!("Peer has no static key"))?;
+    remote_proof.verify(remote_static)?;
+
+    let state = Arc::new(noise.into_stateless_transport_mode()?);
+    let reader = SecureReader {
+        inner: reader,
+        state: state.clone(),
+        nonce: 0,
+        plaintext: Vec::new(),
+    };
+    let writer = SecureWriter {
+        inner: writer,
+        state,
+        nonce: 0,
+    };
+
+    Ok((reader, writer, remote_proof.signer))
+}
+
+async fn write_noise(writer: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> Result<()> {
+    writer.write_u16(message.len() as u16).await?;
+    writer.write_all(message).await?;
+
+    Ok(())
+}
+
+async fn read_noise(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    let mut message = vec![0; len];
+    reader.read_exact(&mut message).await?;
+
+    Ok(message)
+}
+
+/// Receiving half of an encrypted link.
+#[derive(Debug)]
+pub struct SecureReader<R> {
+    inner: R,
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// Decrypted bytes that haven't been returned yet.
+    plaintext: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> SecureReader<R> {
+    /// Read and decode a single frame.
+    pub async fn recv<T: Decode<()>>(&mut self) -> Result<T> {
+        self.fill(4).await?;
+        let len = u32::from_be_bytes(self.plaintext[..4].try_into()?) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(anyhow!("Frame of {len} bytes is too big"));
+        }
+        self.fill(4 + len).await?;
+        let (msg, _) =
+            bincode::decode_from_slice(&self.plaintext[4..4 + len], bincode::config::standard())?;
+        self.plaintext.drain(..4 + len);
+
+        Ok(msg)
+    }
+
+    /// Decrypt Noise messages until at least `len` bytes of plaintext are available.
+    async fn fill(&mut self, len: usize) -> Result<()> {
+        let mut payload = vec![0; MAX_NOISE_LEN];
+        while self.plaintext.len() < len {
+            let message = read_noise(&mut self.inner).await?;
+            let read = self
+                .state
+                .read_message(self.nonce, &message, &mut payload)?;
+            self.nonce += 1;
+            self.plaintext.extend_from_slice(&payload[..read]);
+        }
+
+        Ok(())
+    }
+}
+
+/// Sending half of an encrypted link.
+#[derive(Debug)]
+pub struct SecureWriter<W> {
+    inner: W,
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    /// Encode `msg` and send it as a single frame.
+    pub async fn send<T: Encode>(&mut self, msg: &T) -> Result<()> {
+        let bytes = bincode::encode_to_vec(msg, bincode::config::standard())?;
+        let plaintext = [&(bytes.len() as u32).to_be_bytes(), &bytes[..]].concat();
+        let mut message = vec![0; MAX_NOISE_LEN];
+        for chunk in plaintext.chunks(MAX_NOISE_LEN - TAG_LEN) {
+            let len = self.state.write_message(self.nonce, chunk, &mut message)?;
+            self.nonce += 1;
+            write_noise(&mut self.inner, &message[..len]).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use crate::identity::{KeyStore, TrustError};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_and_exchange_frames() {
+        let (a, b) = duplex(1 << 20);
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+
+        let (a, b) = tokio::join!(
+            handshake(tokio::io::split(a), true, "alice", &alice),
+            handshake(tokio::io::split(b), false, "bob", &bob),
+        );
+        let (_, mut a_writer, a_sees) = a.unwrap();
+        let (mut b_reader, _, b_sees) = b.unwrap();
+        assert_eq!(a_sees.key, bob.public_key());
+        assert_eq!(b_sees.name, "alice");
+
+        // Bigger than a single Noise message.
+        let big = "a".repeat(100_000);
+        a_writer.send(&big).await.unwrap();
+        a_writer.send(&"small".to_owned()).await.unwrap();
+        assert_eq!(b_reader.recv::<String>().await.unwrap(), big);
+        assert_eq!(b_reader.recv::<String>().await.unwrap(), "small");
+    }
+
+    #[tokio::test]
+    async fn rejects_changed_key() {
+        let (a, b) = duplex(1 << 16);
+        let keys = KeyStore::default();
+        let pinned = Signer {
+            name: "bob".to_owned(),
+            key: Identity::generate().public_key(),
+        };
+        keys.check(&pinned).unwrap();
+
+        let (alice, impostor) = (Identity::generate(), Identity::generate());
+        let (a, _) = tokio::join!(
+            handshake(tokio::io::split(a), true, "alice", &alice),
+            handshake(tokio::io::split(b), false, "bob", &impostor),
+        );
+
+        let (_, _, signer) = a.unwrap();
+        assert!(matches!(
+            keys.check(&signer),
+            Err(TrustError::KeyChanged { name, .. }) if name == "bob"
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+//! Sending files to other nodes over the links to them.
+//!
+//! The sender [offers](FileOffer) a file, which the receiver accepts from an offset: the length of what it already
+//! received of it, kept next to the destination in a `.part` file. The sender then streams the rest in chunks, and
+//! once it's all there the receiver checks it against the SHA-256 in the offer before moving it into place. Should
+//! the link break halfway through, accepting the offer again resumes the transfer.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, anyhow};
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use tokio::task::AbortHandle;
+
+use crate::identity::PublicKey;
+
+/// Size of the chunks files are sent in.
+pub const CHUNK_LEN: usize = 64 << 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct FileOffer {
+    /// Chosen by the sender, unique among the files it offers.
+    pub id: u64,
+    /// Name of the file, without its directory. Not to be trusted as a path.
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+impl FileOffer {
+    /// Offer the file at `path`, hashing it.
+    pub fn new(id: u64, path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} isn't a file", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            id,
+            name,
+            size,
+            sha256: hash(&mut file)?,
+        })
+    }
+}
+
+fn hash(file: &mut File) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hash_into(&mut hasher, file)?;
+
+    Ok(hasher.finalize().into())
+}
+
+fn hash_into(hasher: &mut Sha256, file: &mut File) -> Result<()> {
+    let mut buf = vec![0; CHUNK_LEN];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(())
+}
+
+/// Where the part of the offered file received so far is kept while it's being saved to `dest`.
+pub fn part_path(dest: &Path, offer: &FileOffer) -> PathBuf {
+    let mut name = dest.as_os_str().to_owned();
+    name.push(format!(".{}.part", hex::encode(&offer.sha256[..4])));
+    PathBuf::from(name)
+}
+
+/// Read the chunk of the file at `path` starting at `offset`, which is empty at the end of the file. The sender
+/// sends at least one chunk, even if empty, so that a file which is already all there is still finished.
+pub fn read_chunk(path: &Path, offset: u64) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = Vec::with_capacity(CHUNK_LEN);
+    file.take(CHUNK_LEN as u64).read_to_end(&mut chunk)?;
+
+    Ok(chunk)
+}
+
+/// A file offered to this node.
+#[derive(Debug)]
+pub struct Incoming {
+    pub offer: FileOffer,
+    /// Where the file goes, once accepted.
+    dest: Option<PathBuf>,
+    received: u64,
+    /// Hash of what was received so far, so that the file doesn't have to be read again once it's all there.
+    hasher: Sha256,
+}
+
+impl Incoming {
+    pub fn new(offer: FileOffer) -> Self {
+        Self {
+            offer,
+            dest: None,
+            received: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Save the file to `dest`, returning how much of it was already received. Blocks while hashing that part.
+    pub fn accept(&mut self, dest: PathBuf) -> Result<u64> {
+        let part = part_path(&dest, &self.offer);
+        let mut hasher = Sha256::new();
+        let received = match File::open(&part) {
+            Ok(mut file) if file.metadata()?.len() <= self.offer.size => {
+                hash_into(&mut hasher, &mut file)?;
+                file.metadata()?.len()
+            }
+            Ok(_) => {
+                std::fs::remove_file(&part)?;
+                0
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        self.dest = Some(dest);
+        self.received = received;
+        self.hasher = hasher;
+
+        Ok(received)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Write the chunk at `offset`, blocking. Once the whole file is there, it's checked against the offer and, if it
+    /// matches, moved to its destination. Returns whether that's the case, or `None` if the file isn't complete yet.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<Option<bool>> {
+        let dest = self
+            .dest
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} wasn't accepted", self.offer.name))?;
+        if offset != self.received {
+            return Err(anyhow!(
+                "Chunk of {} at {offset}, expected one at {}",
+                self.offer.name,
+                self.received
+            ));
+        }
+        let part = part_path(dest, &self.offer);
+        let mut file = OpenOptions::new().create(true).append(true).open(&part)?;
+        file.write_all(data)?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        if self.received < self.offer.size {
+            return Ok(None);
+        }
+
+        let sha256: [u8; 32] = std::mem::take(&mut self.hasher).finalize().into();
+        let verified = self.received == self.offer.size && sha256 == self.offer.sha256;
+        if verified {
+            std::fs::rename(&part, dest)?;
+        } else {
+            std::fs::remove_file(&part)?;
+        }
+
+        Ok(Some(verified))
+    }
+}
+
+/// The transfers a node is part of, by the key of the other node and the id of the offer.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    /// Files offered to others, and where they are.
+    pub outgoing: HashMap<(PublicKey, u64), (FileOffer, PathBuf)>,
+    /// The tasks sending accepted files, so that a file accepted twice isn't sent twice at once.
+    pub sending: HashMap<(PublicKey, u64), AbortHandle>,
+    /// Files offered by others, each locked on its own so that chunks are written without holding up the others.
+    pub incoming: HashMap<(PublicKey, u64), Arc<Mutex<Incoming>>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_and_verifies() {
+        let dir = std::env::temp_dir().join(format!("chat-async-transfer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.bin");
+        let contents: Vec<u8> = (0..CHUNK_LEN * 2 + 100).map(|i| i as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+        let offer = FileOffer::new(1, &source).unwrap();
+        assert_eq!(
+            (offer.name.as_str(), offer.size),
+            ("source.bin", contents.len() as u64)
+        );
+
+        // The first chunk makes it before the link breaks.
+        let dest = dir.join("dest.bin");
+        let mut incoming = Incoming::new(offer.clone());
+        assert_eq!(incoming.accept(dest.clone()).unwrap(), 0);
+        let chunk = read_chunk(&source, 0).unwrap();
+        assert_eq!(incoming.write(0, &chunk).unwrap(), None);
+
+        let mut incoming = Incoming::new(offer.clone());
+        let mut offset = incoming.accept(dest.clone()).unwrap();
+        assert_eq!(offset, CHUNK_LEN as u64);
+        assert!(incoming.write(0, &chunk).is_err());
+        let mut verified = None;
+        while verified.is_none() {
+            let chunk = read_chunk(&source, offset).unwrap();
+            verified = incoming.write(offset, &chunk).unwrap();
+            offset += chunk.len() as u64;
+        }
+        assert_eq!(verified, Some(true));
+        assert_eq!(std::fs::read(&dest).unwrap(), contents);
+
+        // A file that doesn't match its offer is thrown away.
+        let mut tampered = Incoming::new(FileOffer {
+            sha256: [0; 32],
+            ..offer
+        });
+        let other = dir.join("other.bin");
+        tampered.accept(other.clone()).unwrap();
+        let mut offset = 0;
+        let mut verified = None;
+        while verified.is_none() {
+            let chunk = read_chunk(&source, offset).unwrap();
+            verified = tampered.write(offset, &chunk).unwrap();
+            offset += chunk.len() as u64;
+        }
+        assert_eq!(verified, Some(false));
+        assert!(!other.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Who a node is: an Ed25519 key pair, and the keys other nodes have been seen with.
+
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, anyhow};
+use bincode::{Decode, Encode, de::Decoder, error::DecodeError};
+use ed25519_dalek::{Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use rand::rngs::OsRng;
+use thiserror::Error;
+use tracing::info;
+
+/// The key pair a node signs with. Its [`PublicKey`] identifies the node.
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Read the identity stored at `path`, creating a new one there if it doesn't exist.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let secret = bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("Identity file {} is corrupted", path.display()))?;
+                Ok(Self {
+                    key: SigningKey::from_bytes(&secret),
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(path, identity.key.to_bytes())?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                }
+                info!("Generated new identity {}", identity.public_key());
+                Ok(identity)
+            }
+            Err(e) => Err(e).with_context(|| format!("Reading identity {}", path.display())),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> [u8; 64] {
+        self.key.sign(bytes).to_bytes()
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Identity").field(&self.public_key()).finish()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl PublicKey {
+    /// Check that `signature` was made over `bytes` by this key's [`Identity`].
+    pub fn verify(&self, bytes: &[u8], signature: &[u8; 64]) -> Result<()> {
+        VerifyingKey::from_bytes(&self.0)?
+            .verify(bytes, &ed25519_dalek::Signature::from_bytes(signature))?;
+
+        Ok(())
+    }
+
+    /// Short, human-readable form of the key, to compare out of band.
+    pub fn fingerprint(&self) -> String {
+        self.0[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PublicKey({self})")
+    }
+}
+
+/// Longest name a node may go by.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Whether `name` may be used by a node: not empty, not too long, and without whitespace or control characters, so
+/// that it can't pass for more than one name, like a line of its own in the [`KeyStore`] file.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// A name and the key it claims to belong to. Signers with an [invalid](is_valid_name) name don't decode.
+#[derive(Debug, Clone, PartialEq, Eq, Encode)]
+pub struct Signer {
+    pub name: String,
+    pub key: PublicKey,
+}
+
+impl<Context> Decode<Context> for Signer {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let name = String::decode(decoder)?;
+        if !is_valid_name(&name) {
+            return Err(DecodeError::OtherString(format!("Invalid name {name:?}")));
+        }
+
+        Ok(Self {
+            name,
+            key: PublicKey::decode(decoder)?,
+        })
+    }
+}
+
+bincode::impl_borrow_decode!(Signer);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TrustError {
+    #[error("{name} was previously seen with key {known}, but now presented {presented}")]
+    KeyChanged {
+        name: String,
+        known: PublicKey,
+        presented: PublicKey,
+    },
+    #[error("{0:?} isn't a valid name")]
+    InvalidName(String),
+}
+
+/// Trust-on-first-use store of the key each name was first seen with.
+///
+/// Optionally backed by a file with one `<hex key> <name>` per line.
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    path: Option<PathBuf>,
+    keys: Arc<Mutex<HashMap<String, PublicKey>>>,
+}
+
+impl KeyStore {
+    /// Open the store at `path`, which is created once the first key is trusted.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut keys = HashMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines().filter(|line| !line.is_empty()) {
+                    let (key, name) = line
+                        .split_once(' ')
+                        .ok_or_else(|| anyhow!("Malformed line in {}: {line}", path.display()))?;
+                    let key = hex::decode(key)?
+                        .try_into()
+                        .map_err(|_| anyhow!("Malformed key in {}: {key}", path.display()))?;
+                    if !is_valid_name(name) {
+                        return Err(anyhow!("Invalid name in {}: {name:?}", path.display()));
+                    }
+                    keys.insert(name.to_owned(), PublicKey(key));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        }
+
+        Ok(Self {
+            path: Some(path),
+            keys: Arc::new(Mutex::new(keys)),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<PublicKey> {
+        self.keys.lock().unwrap().get(name).copied()
+    }
+
+    /// Check `signer` against the key its name was first seen with, trusting it if the name is new.
+    pub fn check(&self, signer: &Signer) -> Result<(), TrustError> {
+        if !is_valid_name(&signer.name) {
+            return Err(TrustError::InvalidName(signer.name.clone()));
+        }
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get(&signer.name) {
+            Some(known) if *known == signer.key => Ok(()),
+            Some(known) => Err(TrustError::KeyChanged {
+                name: signer.name.clone(),
+                known: *known,
+                presented: signer.key,
+            }),
+            None => {
+                info!("Trusting {} on first use: {}", signer.name, signer.key);
+                keys.insert(signer.name.clone(), signer.key);
+                if let Err(e) = self.save(&keys) {
+                    tracing::warn!("Couldn't save trusted keys: {e}");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn save(&self, keys: &HashMap<String, PublicKey>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let contents: String = keys
+            .iter()
+            .map(|(name, key)| format!("{key} {name}\n"))
+            .collect();
+        std::fs::write(path, contents)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_verify() {
+        let identity = Identity::generate();
+        let signature = identity.sign(b"hello");
+
+        assert!(identity.public_key().verify(b"hello", &signature).is_ok());
+        assert!(identity.public_key().verify(b"hellO", &signature).is_err());
+        assert!(
+            Identity::generate()
+                .public_key()
+                .verify(b"hello", &signature)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn trusts_on_first_use() {
+        let path = std::env::temp_dir().join(format!("chat-async-keys-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let alice = Signer {
+            name: "alice".to_owned(),
+            key: Identity::generate().public_key(),
+        };
+        let impostor = Signer {
+            name: "alice".to_owned(),
+            key: Identity::generate().public_key(),
+        };
+
+        let store = KeyStore::open(&path).unwrap();
+        assert_eq!(store.check(&alice), Ok(()));
+        assert_eq!(store.check(&alice), Ok(()));
+
+        // The key survives reopening the store.
+        let store = KeyStore::open(&path).unwrap();
+        assert_eq!(store.get("alice"), Some(alice.key));
+        assert_eq!(
+            store.check(&impostor),
+            Err(TrustError::KeyChanged {
+                name: "alice".to_owned(),
+                known: alice.key,
+                presented: impostor.key,
+            })
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn names_cant_pass_for_several() {
+        let key = Identity::generate().public_key();
+        let forged = Signer {
+            name: format!("mallory\n{key} alice"),
+            key,
+        };
+        let store = KeyStore::default();
+        assert_eq!(
+            store.check(&forged),
+            Err(TrustError::InvalidName(forged.name.clone()))
+        );
+        assert_eq!(store.get("alice"), None);
+
+        let bytes = bincode::encode_to_vec(&forged, bincode::config::standard()).unwrap();
+        assert!(
+            bincode::decode_from_slice::<Signer, _>(&bytes, bincode::config::standard()).is_err()
+        );
+        assert!(!is_valid_name(&"a".repeat(MAX_NAME_LEN + 1)));
+        assert!(is_valid_name("alice"));
+    }
+}
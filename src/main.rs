@@ -1,16 +1,31 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
 use chat_async::{
-    MULTICAST_ADDRESS,
+    chat::{ChatMessage, DEFAULT_CHANNEL},
     connect::{
+        admission::Cidr,
+        discovery::Discovery,
         get_my_ip::get_my_ip,
-        multicast::{
-            communicator::IpcCommunicator, message::MulticastMessage, server::MulticastServer,
-        },
+        manager::{Event, LinkState, PeerId},
+        multicast::communicator::{FallbackCommunicator, IpcCommunicator},
+        transfer::{CHUNK_LEN, FileOffer},
     },
+    identity::{Identity, KeyStore},
+    node::{Node, NodeConfig},
+    room::RoomKey,
+    store::StoredMessage,
 };
-use tokio::{net::TcpListener, sync::mpsc};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tracing::info;
 
+/// Finds other nodes through the multicast, shared by every node on this computer, or through broadcast if no one is
+/// heard on it.
+type ChatNode = Node<FallbackCommunicator<IpcCommunicator>>;
+
 // Initialize the Runtime manually because `procspawn::init()` must be the first thing called, otherwise a runtime
 // already exists but we can't get a handle to it
 fn main() -> Result<()> {
@@ -19,41 +34,137 @@ fn main() -> Result<()> {
         let subscriber = tracing_subscriber::FmtSubscriber::new();
         tracing::subscriber::set_global_default(subscriber)?;
 
-        let (tx, _rx) = mpsc::channel::<MulticastMessage>(8);
-
-        let mut multicast_server =
-            <MulticastServer<_, IpcCommunicator>>::join(MULTICAST_ADDRESS, tx).await?;
-        let listener = TcpListener::bind("0.0.0.0:0").await?;
-        let port = listener.local_addr()?.port();
-        let _my_ip = get_my_ip().await?;
-
-        info!("Sending listener port ({port}) to multicast.");
-        multicast_server
-            .send(MulticastMessage::NewServer { port })
-            .await?;
+        let mut name = None;
+        let mut strict = false;
+        let mut legacy_hi = false;
+        let mut discovery = Discovery::default();
+        let mut room = None;
+        let (mut allow, mut deny) = (Vec::new(), Vec::new());
+        let mut advertise = Vec::new();
+        let mut peers = Vec::new();
+        let mut seed = false;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--strict" => strict = true,
+                "--legacy-hi" => legacy_hi = true,
+                "--mdns" => discovery = Discovery::Mdns,
+                "--seed" => seed = true,
+                "--peer" => peers.push(args.next().ok_or_else(|| {
+                    anyhow!("--peer needs an address, like lab.example.com:4983")
+                })?),
+                "--room" => {
+                    let passphrase = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--room needs a passphrase"))?;
+                    room = Some(RoomKey::from_passphrase(&passphrase));
+                }
+                "--allow" | "--deny" => {
+                    let range: Cidr = args
+                        .next()
+                        .ok_or_else(|| anyhow!("{arg} needs an address range, like 10.0.0.0/8"))?
+                        .parse()?;
+                    if arg == "--allow" {
+                        allow.push(range);
+                    } else {
+                        deny.push(range);
+                    }
+                }
+                "--advertise" => advertise.push(
+                    args.next()
+                        .ok_or_else(|| {
+                            anyhow!("--advertise needs an address, like 203.0.113.7:4000")
+                        })?
+                        .parse()?,
+                ),
+                _ if arg.starts_with("--") => return Err(anyhow!("Unknown option {arg}")),
+                _ => name = Some(arg),
+            }
+        }
+        let name = name
+            .or_else(|| std::env::var("USER").ok())
+            .unwrap_or_else(|| "anonymous".to_owned());
+        let my_ip = get_my_ip().await?;
 
-        /*  multicast_server
-        .send(
-            &[[b'H', b'I'], port.to_be_bytes()].concat(),
-        )
-        .await?; */
+        let config_dir = PathBuf::from(std::env::var("HOME")?).join(".config/chat-async");
+        let mut config = NodeConfig::new(name, my_ip);
+        config.identity = Identity::load_or_generate(&config_dir.join("identity"))?;
+        config.keys = KeyStore::open(config_dir.join("known_keys"))?;
+        config.strict = strict;
+        config.legacy_hi = legacy_hi;
+        config.discovery = discovery;
+        config.room = room;
+        config.admission.allow = allow;
+        config.admission.deny = deny;
+        config.advertise = advertise;
+        config.peers = peers;
+        config.seed = seed;
+        config.store = Some(config_dir.join("messages"));
+        config.outbox = Some(config_dir.join("outbox"));
 
-        /* let (tx, rx) = tokio::sync::mpsc::channel(8);
-        let tx1 = tx.clone();
-        let handle_new_multicast_members =
-            tokio::spawn(handle_new_multicast_members(tx1, multicast, my_ip));
+        let (node, mut events) = ChatNode::start(config).await?;
+        info!("Listening on {}", node.id());
+        print_backlog(&node, DEFAULT_CHANNEL);
 
-        let handle_incoming_connections = tokio::spawn(handle_incoming_connections(tx, listener));
+        let mut session = Session {
+            current: Some(DEFAULT_CHANNEL.to_owned()),
+            pending: HashMap::new(),
+            offers: Vec::new(),
+            downloads: PathBuf::from(std::env::var("HOME")?).join("Downloads"),
+        };
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            tokio::select! {
+                line = lines.next_line() => match line? {
+                    Some(line) => run_command(&node, &mut session, &line).await,
+                    None => break,
+                },
+                Some(event) = events.recv() => match event {
+                    Event::PeerConnected { peer, name, key } => {
+                        println!("* {name} ({peer}, {}) joined", key.fingerprint())
+                    }
+                    Event::PeerDisconnected { peer } => println!("* {peer} left"),
+                    Event::Message { message, .. } => print_message(&message),
+                    Event::PrivateMessage { name, key, message, .. } => {
+                        println!("*{name}* ({}) {}", key.fingerprint(), message.text)
+                    }
+                    Event::Delivered { peer, id } => {
+                        match session.pending.remove(&id) {
+                            Some(name) => println!("* {name} received your message"),
+                            None => println!("* {peer} received a message sent earlier"),
+                        }
+                    }
+                    Event::FileOffered { from, offer } => {
+                        let n = session.offers.len();
+                        println!(
+                            "* {from} offers {} ({} bytes). /accept {n} or /decline {n}",
+                            offer.name, offer.size
+                        );
+                        session.offers.push((from, offer));
+                    }
+                    Event::FileDeclined { peer, id } => println!("* {peer} declined file {id}"),
+                    Event::TransferProgress { peer, id, done, size } => {
+                        // Every mebibyte or so.
+                        if done % (1 << 20) < CHUNK_LEN as u64 {
+                            println!("* File {id} with {peer}: {}%", done * 100 / size.max(1));
+                        }
+                    }
+                    Event::TransferFinished { peer, id, verified: true } => {
+                        println!("* File {id} with {peer} done")
+                    }
+                    Event::TransferFinished { peer, id, verified: false } => {
+                        println!("* File {id} from {peer} didn't match what was offered and was discarded")
+                    }
+                    Event::Link { peer, state: LinkState::BackingOff { delay, .. } } => {
+                        println!("* Couldn't reach {peer}. Trying again in {}s", delay.as_secs_f32().ceil())
+                    }
+                    Event::Link { peer, state: LinkState::GaveUp } => println!("* Gave up on {peer}"),
+                    Event::Link { .. } => {}
+                },
+            }
+        }
 
-        let manage_tcp_streams = tokio::spawn(manage_tcp_streams(rx));
-
-        let (first, second, third) = tokio::join!(handle_new_multicast_members, handle_incoming_connections, manage_tcp_streams);
-
-        first??;
-        second??;
-        third??; */
-
-        Ok(())
+        node.shutdown().await
     };
 
     tokio::runtime::Builder::new_multi_thread()
@@ -62,3 +173,149 @@ fn main() -> Result<()> {
         .expect("Failed building the Runtime")
         .block_on(body)
 }
+
+/// How many stored messages are shown when entering a channel.
+const BACKLOG_LEN: usize = 20;
+
+fn print_message(message: &ChatMessage) {
+    println!("#{} <{}> {}", message.channel, message.author, message.text)
+}
+
+/// Show the latest stored messages of `channel`.
+fn print_backlog(node: &ChatNode, channel: &str) {
+    match node.stored_messages(channel, BACKLOG_LEN) {
+        Ok(stored) => {
+            for StoredMessage { message, .. } in stored {
+                print_message(&message);
+            }
+        }
+        Err(e) => println!("* Couldn't read the stored messages of #{channel}: {e}"),
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    /// The channel lines typed without a command are sent to.
+    current: Option<String>,
+    /// Who each private message still waiting to be acknowledged was sent to.
+    pending: HashMap<u64, String>,
+    /// Every file offered, numbered by their position.
+    offers: Vec<(PeerId, FileOffer)>,
+    /// Where accepted files go.
+    downloads: PathBuf,
+}
+
+/// Handle a line typed by the user: either a command or a message to the current channel.
+async fn run_command(node: &ChatNode, session: &mut Session, line: &str) {
+    let current = &mut session.current;
+    let channel_arg =
+        |arg: Option<&str>| arg.map(|channel| channel.trim_start_matches('#').to_owned());
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("/join") => match channel_arg(words.next()) {
+            Some(channel) => {
+                match node.join_channel(&channel).await {
+                    Ok(true) => print_backlog(node, &channel),
+                    Ok(false) => println!("* Already in #{channel}"),
+                    Err(e) => {
+                        println!("* Couldn't join #{channel}: {e}");
+                        return;
+                    }
+                }
+                println!("* Talking in #{channel}");
+                *current = Some(channel);
+            }
+            None => println!("* Usage: /join <channel>"),
+        },
+        Some("/part") => match channel_arg(words.next()).or_else(|| current.clone()) {
+            Some(channel) => {
+                if !node.part_channel(&channel).await {
+                    println!("* Not in #{channel}");
+                } else if current.as_ref() == Some(&channel) {
+                    *current = node.channels().into_iter().next();
+                    match current {
+                        Some(channel) => println!("* Talking in #{channel}"),
+                        None => println!("* Not in any channel. /join one to talk."),
+                    }
+                }
+            }
+            None => println!("* Usage: /part [channel]"),
+        },
+        Some("/msg") => match (words.next(), words.collect::<Vec<_>>().join(" ")) {
+            (Some(name), text) if !text.is_empty() => {
+                match node.send_private(name, text.clone()).await {
+                    Ok(sent) => {
+                        println!("-> *{name}* {text}");
+                        if sent.queued {
+                            println!(
+                                "* {name} is offline. The message will be sent once they're back."
+                            );
+                        }
+                        session.pending.insert(sent.id, name.to_owned());
+                    }
+                    Err(e) => println!("* Couldn't send: {e}"),
+                }
+            }
+            _ => println!("* Usage: /msg <nick> <text>"),
+        },
+        // The path is the rest of the line as it was typed, spaces included.
+        Some("/send") => match line.trim_start().splitn(3, ' ').skip(1).collect::<Vec<_>>()[..] {
+            [name, path] if !name.is_empty() && !path.is_empty() => {
+                match node.offer_file(name, Path::new(path)).await {
+                    Ok(offer) => println!("* Offered {} to {name}", offer.name),
+                    Err(e) => println!("* Couldn't offer {path}: {e}"),
+                }
+            }
+            _ => println!("* Usage: /send <nick> <path>"),
+        },
+        Some(command @ ("/accept" | "/decline")) => {
+            let offer = words
+                .next()
+                .and_then(|n| n.parse::<usize>().ok())
+                .and_then(|n| session.offers.get(n));
+            let Some((from, offer)) = offer else {
+                println!("* Usage: {command} <number of the offer>");
+                return;
+            };
+            if command == "/decline" {
+                if let Err(e) = node.decline_file(*from, offer.id).await {
+                    println!("* Couldn't decline: {e}");
+                }
+                return;
+            }
+            // The name comes from the other node, so only its last component is used.
+            let name = Path::new(&offer.name).file_name().map_or_else(
+                || format!("file-{}", offer.id).into(),
+                |name| name.to_owned(),
+            );
+            let dest = session.downloads.join(name);
+            if let Err(e) = std::fs::create_dir_all(&session.downloads) {
+                println!("* Couldn't create {}: {e}", session.downloads.display());
+                return;
+            }
+            match node.accept_file(*from, offer.id, dest.clone()).await {
+                Ok(0) => println!("* Receiving {}", dest.display()),
+                Ok(offset) => println!("* Resuming {} after {offset} bytes", dest.display()),
+                Err(e) => println!("* Couldn't accept: {e}"),
+            }
+        }
+        Some("/connect") => match words.next() {
+            Some(addr) => match node.connect(addr).await {
+                Ok(peer) => println!("* Connecting to {peer}"),
+                Err(e) => println!("* Couldn't connect to {addr}: {e}"),
+            },
+            None => println!("* Usage: /connect <host:port>"),
+        },
+        Some("/list") => {
+            for (channel, members) in node.network_channels() {
+                println!("* #{channel}: {} nodes", members.len());
+            }
+        }
+        _ => match current {
+            Some(channel) => {
+                node.send(channel, line).await;
+            }
+            None => println!("* Not in any channel. /join one to talk."),
+        },
+    }
+}
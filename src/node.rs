@@ -0,0 +1,300 @@
+//! A whole chat node: a [`TcpListener`] for other nodes to connect to, a [`MulticastServer`] (or an [`MdnsServer`])
+//! to find them, and the [`ConnectionManager`] holding the links.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+use crate::{
+    MULTICAST_ADDRESS,
+    chat::MessageId,
+    connect::{
+        admission::AdmissionPolicy,
+        discovery::{Discovery, discover_peers},
+        manager::{ConnectionManager, Event, JoinError, Outgoing, PeerId, SendError},
+        mdns::{MDNS_ADDRESS, MdnsServer},
+        multicast::{
+            communicator::{Communicator, SocketCommunicator},
+            limit::RateLimit,
+            server::{MulticastServer, Security},
+        },
+        mux::{QueuePolicy, QueueStats},
+        outbox::Outbox,
+        transfer::FileOffer,
+    },
+    handle_incoming_connections,
+    identity::{Identity, KeyStore, MAX_NAME_LEN, is_valid_name},
+    room::RoomKey,
+    store::{MessageStore, Retention, StoredMessage},
+};
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Name shown to other nodes.
+    pub name: String,
+    /// IP other nodes can reach this one at.
+    pub ip: Ipv4Addr,
+    /// How to find other nodes.
+    pub discovery: Discovery,
+    /// Multicast used to find other nodes with [`Discovery::Multicast`].
+    pub multicast: SocketAddrV4,
+    /// Where mDNS is spoken, for [`Discovery::Mdns`].
+    pub mdns: SocketAddrV4,
+    /// Key pair multicast announcements and links are authenticated with.
+    pub identity: Identity,
+    /// Keys other nodes' names were first seen with.
+    pub keys: KeyStore,
+    /// Ignore announcements that aren't properly signed. See [`Security::strict`].
+    pub strict: bool,
+    /// Private room to join. Only nodes with the same room key are discovered and connected to.
+    pub room: Option<RoomKey>,
+    /// Directory to keep messages in across restarts. Without one, they're only kept in memory.
+    pub store: Option<PathBuf>,
+    /// How long stored messages are kept.
+    pub retention: Retention,
+    /// File to keep private messages in until their recipients acknowledge them. Without one, they're lost on exit.
+    pub outbox: Option<PathBuf>,
+    /// What to do with frames for a peer that stopped reading them.
+    pub queue_policy: QueuePolicy,
+    /// How many multicast messages each other node may send.
+    pub multicast_limit: RateLimit,
+    /// Which nodes, and how many of them, may be linked with.
+    pub admission: AdmissionPolicy,
+    /// Also discover older nodes announcing themselves with a legacy `HI`. See [`Security::legacy_hi`].
+    pub legacy_hi: bool,
+    /// Other addresses this node may be reached at, such as those of a port forwarded to it, the preferred first.
+    /// Announced along with its [`ip`](Self::ip) and port. Only IPv4 addresses, as links are only made over IPv4.
+    pub advertise: Vec<SocketAddr>,
+    /// Nodes to link to whether or not they're discovered, as `host:port`, such as those across a VPN.
+    pub peers: Vec<String>,
+    /// Tell every node that links to this one about the others. See [`ConnectionManager::set_seed`].
+    pub seed: bool,
+}
+
+impl NodeConfig {
+    /// Configuration with a freshly generated identity and an in-memory key store.
+    pub fn new(name: impl Into<String>, ip: Ipv4Addr) -> Self {
+        Self {
+            name: name.into(),
+            ip,
+            discovery: Discovery::default(),
+            multicast: MULTICAST_ADDRESS,
+            mdns: MDNS_ADDRESS,
+            identity: Identity::generate(),
+            keys: KeyStore::default(),
+            strict: false,
+            room: None,
+            store: None,
+            retention: Retention::default(),
+            outbox: None,
+            queue_policy: QueuePolicy::default(),
+            multicast_limit: RateLimit::default(),
+            admission: AdmissionPolicy::default(),
+            legacy_hi: false,
+            advertise: Vec::new(),
+            peers: Vec::new(),
+            seed: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Node<C: Communicator = SocketCommunicator> {
+    manager: ConnectionManager,
+    shutdown: Option<oneshot::Sender<()>>,
+    discovery: JoinHandle<Result<()>>,
+    tasks: Vec<JoinHandle<Result<()>>>,
+    _communicator: std::marker::PhantomData<C>,
+}
+
+impl<C: Communicator> Node<C> {
+    /// Start listening for other nodes and announce this one on the multicast.
+    #[tracing::instrument(name = "Node::start")]
+    pub async fn start(config: NodeConfig) -> Result<(Self, mpsc::UnboundedReceiver<Event>)> {
+        if let Some(endpoint) = config.advertise.iter().find(|endpoint| endpoint.is_ipv6()) {
+            return Err(anyhow!(
+                "Can't advertise {endpoint}: links are only made over IPv4"
+            ));
+        }
+        if !is_valid_name(&config.name) {
+            return Err(anyhow!(
+                "{:?} can't be used as a name: it must be at most {MAX_NAME_LEN} bytes, without spaces",
+                config.name
+            ));
+        }
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let me = SocketAddrV4::new(config.ip, listener.local_addr()?.port());
+        let (manager, events) = ConnectionManager::new(
+            me,
+            config.name.clone(),
+            config.identity.clone(),
+            config.keys.clone(),
+            config.room.clone(),
+        );
+        if let Some(dir) = &config.store {
+            manager.attach_store(MessageStore::open(dir, config.retention.clone())?)?;
+        }
+        manager.set_queue_policy(config.queue_policy);
+        manager.set_admission(config.admission.clone());
+        manager.set_advertised(config.advertise.clone());
+        manager.set_seed(config.seed);
+        if let Some(path) = &config.outbox {
+            manager.attach_outbox(Outbox::open(path)?);
+        }
+
+        let (tx, rx) = mpsc::channel(8);
+        let (msg_tx, msg_rx) = mpsc::channel(8);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let security = Security {
+            identity: Some((config.name.clone(), config.identity.clone())),
+            keys: config.keys.clone(),
+            strict: config.strict,
+            room: config.room.clone(),
+            rate_limit: config.multicast_limit,
+            legacy_hi: config.legacy_hi,
+        };
+
+        let tasks = vec![
+            tokio::spawn(handle_incoming_connections(tx, listener)),
+            tokio::spawn(manager.clone().manage(rx)),
+            tokio::spawn(manager.clone().release_held_back()),
+        ];
+        let discovery = match config.discovery {
+            Discovery::Multicast => {
+                let server =
+                    <MulticastServer<_, C>>::join_with(config.multicast, msg_tx, security).await?;
+                tokio::spawn(discover_peers(server, msg_rx, manager.clone(), shutdown_rx))
+            }
+            Discovery::Mdns => {
+                let server = <MdnsServer<C>>::join(config.mdns, msg_tx, security).await?;
+                tokio::spawn(discover_peers(server, msg_rx, manager.clone(), shutdown_rx))
+            }
+        };
+
+        let node = Self {
+            manager,
+            shutdown: Some(shutdown),
+            discovery,
+            tasks,
+            _communicator: std::marker::PhantomData,
+        };
+        for peer in &config.peers {
+            if let Err(e) = node.connect(peer).await {
+                warn!("Not connecting to {peer}: {e}");
+            }
+        }
+
+        Ok((node, events))
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.manager.me()
+    }
+
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.manager.peers()
+    }
+
+    /// Link to the node at `addr`, a `host:port`, as if it was discovered. See [`ConnectionManager::connect`].
+    pub async fn connect(&self, addr: &str) -> Result<PeerId> {
+        let peer = tokio::net::lookup_host(addr)
+            .await?
+            .find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| anyhow!("{addr} has no IPv4 address"))?;
+        self.manager.connect(peer);
+
+        Ok(peer)
+    }
+
+    /// How full the outbound queue of each link is.
+    pub fn queue_stats(&self) -> HashMap<PeerId, QueueStats> {
+        self.manager.queue_stats()
+    }
+
+    /// The channels this node is in. Every node starts in [`DEFAULT_CHANNEL`](crate::chat::DEFAULT_CHANNEL).
+    pub fn channels(&self) -> BTreeSet<String> {
+        self.manager.channels()
+    }
+
+    /// Every channel someone on the network is in, and who is in it.
+    pub fn network_channels(&self) -> BTreeMap<String, Vec<PeerId>> {
+        self.manager.network_channels()
+    }
+
+    /// Start receiving the messages sent to `channel`. Returns whether this node wasn't in it already.
+    pub async fn join_channel(&self, channel: &str) -> Result<bool, JoinError> {
+        self.manager.join(channel).await
+    }
+
+    /// Stop receiving the messages sent to `channel`. Returns whether this node was in it.
+    pub async fn part_channel(&self, channel: &str) -> bool {
+        self.manager.part(channel).await
+    }
+
+    /// Send `text` to every node in `channel`. See [`ConnectionManager::publish`].
+    pub async fn send(&self, channel: &str, text: impl Into<String>) -> MessageId {
+        self.manager.publish(channel, text.into()).await
+    }
+
+    /// Send `text` only to the node called `name`. See [`ConnectionManager::send_private`].
+    pub async fn send_private(
+        &self,
+        name: &str,
+        text: impl Into<String>,
+    ) -> Result<Outgoing, SendError> {
+        self.manager.send_private(name, text.into()).await
+    }
+
+    /// Offer the file at `path` to the node called `name`. See [`ConnectionManager::offer_file`].
+    pub async fn offer_file(&self, name: &str, path: &Path) -> Result<FileOffer> {
+        self.manager.offer_file(name, path).await
+    }
+
+    /// Accept the file `from` offered with this id. See [`ConnectionManager::accept_file`].
+    pub async fn accept_file(&self, from: PeerId, id: u64, dest: PathBuf) -> Result<u64> {
+        self.manager.accept_file(from, id, dest).await
+    }
+
+    pub async fn decline_file(&self, from: PeerId, id: u64) -> Result<()> {
+        self.manager.decline_file(from, id).await
+    }
+
+    /// The latest `limit` messages of `channel` kept in the [`store`](NodeConfig::store), oldest first.
+    pub fn stored_messages(&self, channel: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        self.manager.stored_messages(channel, limit)
+    }
+
+    /// Announce that this node is leaving and close every link.
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        // Wait for the close to be announced before dropping the links.
+        (&mut self.discovery).await??;
+        self.manager.disconnect_all();
+
+        Ok(())
+    }
+}
+
+impl<C: Communicator> Drop for Node<C> {
+    fn drop(&mut self) {
+        self.discovery.abort();
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Private rooms: nodes configured with the same passphrase only see and talk to each other.
+
+use std::fmt::Debug;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Salt for deriving [`RoomKey`]s, so that the keys are specific to this application.
+const SALT: &[u8] = b"chat-async room";
+
+/// Iterations of PBKDF2, to slow down guessing a passphrase from sniffed multicast messages.
+const ROUNDS: u32 = 100_000;
+
+/// Key derived from a room passphrase, with which multicast messages and link proofs are authenticated.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RoomKey([u8; 32]);
+
+impl RoomKey {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), SALT, ROUNDS, &mut key);
+
+        Self(key)
+    }
+
+    /// HMAC-SHA256 of all of `parts`, in order.
+    pub fn mac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut mac = self.hmac();
+        for part in parts {
+            mac.update(part);
+        }
+
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Check, in constant time, that `tag` is the [`mac`](Self::mac) of `parts`.
+    pub fn verify(&self, parts: &[&[u8]], tag: &[u8; 32]) -> bool {
+        let mut mac = self.hmac();
+        for part in parts {
+            mac.update(part);
+        }
+
+        mac.verify_slice(tag).is_ok()
+    }
+
+    fn hmac(&self) -> Hmac<Sha256> {
+        <Hmac<Sha256>>::new_from_slice(&self.0).expect("HMAC accepts keys of any length")
+    }
+}
+
+impl Debug for RoomKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RoomKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_passphrase_same_key() {
+        let key = RoomKey::from_passphrase("fourth floor");
+        let tag = key.mac(&[b"hello", b"world"]);
+
+        assert!(RoomKey::from_passphrase("fourth floor").verify(&[b"hello", b"world"], &tag));
+        assert!(!RoomKey::from_passphrase("third floor").verify(&[b"hello", b"world"], &tag));
+        assert!(!key.verify(&[b"hello", b"there"], &tag));
+    }
+}
@@ -0,0 +1,478 @@
+//! Messages kept on disk, so that conversations survive restarts.
+//!
+//! The store is an append-only log split into segments named `<number>.log`. Each record is
+//! `[len: u32][checksum: 8 bytes][bincode StoredMessage]`, the checksum being the start of the SHA-256 of the rest.
+//! A record that doesn't check out ends its segment. In the last one, being written to, it was likely cut short by
+//! a crash, and it's dropped along with everything after it. Earlier ones are left as they are on disk, but nothing
+//! past it is read. Old segments are deleted according to the [`Retention`] policy.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{chat::ChatMessage, connect::frame::MAX_FRAME_LEN};
+
+/// Size past which a new segment is started.
+const SEGMENT_LEN: u64 = 4 << 20;
+
+const CHECKSUM_LEN: usize = 8;
+
+/// Which messages are deleted. Whole segments are deleted at once, never the one being written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retention {
+    /// Delete the segments whose messages are all older than this.
+    pub max_age: Option<Duration>,
+    /// Keep at most this many segments, deleting the oldest ones.
+    pub max_segments: Option<usize>,
+}
+
+impl Default for Retention {
+    /// Keep a month of messages.
+    fn default() -> Self {
+        Self {
+            max_age: Some(Duration::from_secs(30 * 24 * 60 * 60)),
+            max_segments: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct StoredMessage {
+    /// Milliseconds since the Unix epoch when the message was stored.
+    pub stored_at: u64,
+    pub message: ChatMessage,
+}
+
+/// Where a record is.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Default)]
+struct Segment {
+    len: u64,
+    /// When the newest message in it was stored.
+    newest: u64,
+}
+
+/// Index key: when a message was stored, then the order it was stored in.
+type Key = (u64, u64);
+
+#[derive(Debug)]
+pub struct MessageStore {
+    dir: PathBuf,
+    retention: Retention,
+    segment_len: u64,
+    segments: BTreeMap<u64, Segment>,
+    /// The last segment, appended to.
+    file: File,
+    by_channel: BTreeMap<String, BTreeMap<Key, Location>>,
+    by_time: BTreeMap<Key, Location>,
+    next_record: u64,
+}
+
+impl MessageStore {
+    /// Open the store in `dir`, creating it if needed, and apply the `retention` policy to it.
+    pub fn open(dir: impl Into<PathBuf>, retention: Retention) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+        let mut numbers = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(number) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(|number| number.parse::<u64>().ok())
+            {
+                numbers.push(number);
+            }
+        }
+        numbers.sort();
+        let last = numbers.last().copied().unwrap_or(0);
+        let file = open_segment(&dir, last)?;
+
+        let mut store = Self {
+            dir,
+            retention,
+            segment_len: SEGMENT_LEN,
+            segments: BTreeMap::new(),
+            file,
+            by_channel: BTreeMap::new(),
+            by_time: BTreeMap::new(),
+            next_record: 0,
+        };
+        for number in numbers {
+            store.load_segment(number, number == last)?;
+        }
+        store.segments.entry(last).or_default();
+        store.apply_retention(now_millis())?;
+        info!(
+            "Loaded {} stored messages from {}",
+            store.by_time.len(),
+            store.dir.display()
+        );
+
+        Ok(store)
+    }
+
+    /// Index every record of a segment up to the first one that doesn't check out, cutting it there if it's the
+    /// `last` one.
+    fn load_segment(&mut self, number: u64, last: bool) -> Result<()> {
+        let path = segment_path(&self.dir, number);
+        let mut bytes =
+            std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+        let mut segment = Segment::default();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match decode_record(&bytes[offset..]) {
+                Some((stored, len)) => {
+                    self.index(&stored, number, offset as u64);
+                    segment.newest = segment.newest.max(stored.stored_at);
+                    offset += len;
+                }
+                None if last => {
+                    warn!(
+                        "{} is corrupted after {offset} bytes. Dropping the {} bytes after that.",
+                        path.display(),
+                        bytes.len() - offset
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(offset as u64)?;
+                    bytes.truncate(offset);
+                    break;
+                }
+                None => {
+                    warn!(
+                        "{} is corrupted after {offset} bytes. Skipping the {} bytes after that.",
+                        path.display(),
+                        bytes.len() - offset
+                    );
+                    break;
+                }
+            }
+        }
+        segment.len = bytes.len() as u64;
+        self.segments.insert(number, segment);
+
+        Ok(())
+    }
+
+    fn index(&mut self, stored: &StoredMessage, segment: u64, offset: u64) {
+        let key = (stored.stored_at, self.next_record);
+        self.next_record += 1;
+        let location = Location { segment, offset };
+        self.by_channel
+            .entry(stored.message.channel.clone())
+            .or_default()
+            .insert(key, location);
+        self.by_time.insert(key, location);
+    }
+
+    /// Add `message` at the end of the log.
+    pub fn append(&mut self, message: &ChatMessage) -> Result<()> {
+        let stored = StoredMessage {
+            stored_at: now_millis(),
+            message: message.clone(),
+        };
+        let payload = bincode::encode_to_vec(&stored, bincode::config::standard())?;
+        let record = [
+            &(payload.len() as u32).to_be_bytes()[..],
+            &checksum(&payload),
+            &payload,
+        ]
+        .concat();
+
+        let (&number, segment) = self.segments.last_key_value().unwrap();
+        if segment.len > 0 && segment.len + record.len() as u64 > self.segment_len {
+            self.file = open_segment(&self.dir, number + 1)?;
+            self.segments.insert(number + 1, Segment::default());
+            self.apply_retention(stored.stored_at)?;
+        }
+        let (&number, segment) = self.segments.last_key_value().unwrap();
+        let offset = segment.len;
+        self.file.write_all(&record)?;
+        self.index(&stored, number, offset);
+        let segment = self.segments.get_mut(&number).unwrap();
+        segment.len += record.len() as u64;
+        segment.newest = stored.stored_at;
+
+        Ok(())
+    }
+
+    /// The latest `limit` messages of `channel` stored at or after `since` (in milliseconds since the Unix epoch),
+    /// oldest first.
+    pub fn channel(&self, channel: &str, since: u64, limit: usize) -> Result<Vec<StoredMessage>> {
+        let Some(index) = self.by_channel.get(channel) else {
+            return Ok(Vec::new());
+        };
+        self.read_latest(index.range((since, 0)..), limit)
+    }
+
+    /// The latest `limit` messages of every channel, oldest first.
+    pub fn recent(&self, limit: usize) -> Result<Vec<StoredMessage>> {
+        self.read_latest(self.by_time.iter(), limit)
+    }
+
+    fn read_latest<'a>(
+        &self,
+        locations: impl DoubleEndedIterator<Item = (&'a Key, &'a Location)>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>> {
+        let mut messages = locations
+            .rev()
+            .take(limit)
+            .map(|(_, location)| self.read(*location))
+            .collect::<Result<Vec<_>>>()?;
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    fn read(&self, location: Location) -> Result<StoredMessage> {
+        let mut file = File::open(segment_path(&self.dir, location.segment))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut header = [0; 4 + CHECKSUM_LEN];
+        file.read_exact(&mut header)?;
+        let len = u32::from_be_bytes(header[..4].try_into()?) as usize;
+        let mut record = header.to_vec();
+        record.resize(header.len() + len, 0);
+        file.read_exact(&mut record[header.len()..])?;
+
+        decode_record(&record)
+            .map(|(stored, _)| stored)
+            .ok_or_else(|| anyhow!("Stored message changed on disk"))
+    }
+
+    /// Delete the segments the [`Retention`] policy doesn't keep, as of `now`.
+    fn apply_retention(&mut self, now: u64) -> Result<()> {
+        let active = *self.segments.last_key_value().unwrap().0;
+        let too_old = |segment: &Segment| {
+            self.retention.max_age.is_some_and(|max_age| {
+                segment.newest < now.saturating_sub(max_age.as_millis() as u64)
+            })
+        };
+        let too_many = self
+            .retention
+            .max_segments
+            .map_or(0, |max| self.segments.len().saturating_sub(max.max(1)));
+        let expired: Vec<u64> = self
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|&(i, (&number, segment))| {
+                number != active && (i < too_many || too_old(segment))
+            })
+            .map(|(_, (&number, _))| number)
+            .collect();
+
+        for number in expired {
+            info!("Deleting expired message segment {number}");
+            std::fs::remove_file(segment_path(&self.dir, number))?;
+            self.segments.remove(&number);
+            self.by_time
+                .retain(|_, location| location.segment != number);
+            for index in self.by_channel.values_mut() {
+                index.retain(|_, location| location.segment != number);
+            }
+        }
+        self.by_channel.retain(|_, index| !index.is_empty());
+
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{number:08}.log"))
+}
+
+fn open_segment(dir: &Path, number: u64) -> Result<File> {
+    let path = segment_path(dir, number);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Opening {}", path.display()))
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(payload)[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+/// Decode the record at the start of `bytes`, returning it and its length, unless it's incomplete or corrupted.
+fn decode_record(bytes: &[u8]) -> Option<(StoredMessage, usize)> {
+    let header = 4 + CHECKSUM_LEN;
+    let len = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    if len > MAX_FRAME_LEN {
+        return None;
+    }
+    let payload = bytes.get(header..header + len)?;
+    if checksum(payload)[..] != bytes[4..header] {
+        return None;
+    }
+    let (stored, _) = bincode::decode_from_slice(payload, bincode::config::standard()).ok()?;
+
+    Some((stored, header + len))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chat::MessageId,
+        connect::{causal::VectorClock, gossip::DEFAULT_TTL},
+        identity::Identity,
+    };
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-async-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn messages(channel: &str, n: u64) -> Vec<ChatMessage> {
+        let origin = Identity::generate().public_key();
+        (1..=n)
+            .map(|seq| ChatMessage {
+                id: MessageId { origin, seq },
+                ttl: DEFAULT_TTL,
+                deps: VectorClock::default(),
+                channel: channel.to_owned(),
+                author: "alice".to_owned(),
+                text: format!("message {seq}"),
+            })
+            .collect()
+    }
+
+    fn texts(stored: &[StoredMessage]) -> Vec<&str> {
+        stored.iter().map(|s| s.message.text.as_str()).collect()
+    }
+
+    #[test]
+    fn reloads_messages_by_channel() {
+        let dir = temp_dir("store-reload");
+        let mut store = MessageStore::open(&dir, Retention::default()).unwrap();
+        for (general, rust) in messages("general", 3)
+            .iter()
+            .zip(messages("rust", 3).iter())
+        {
+            store.append(general).unwrap();
+            store.append(rust).unwrap();
+        }
+
+        let store = MessageStore::open(&dir, Retention::default()).unwrap();
+        let general = store.channel("general", 0, 2).unwrap();
+        assert_eq!(texts(&general), ["message 2", "message 3"]);
+        assert_eq!(store.channel("rust", 0, 10).unwrap().len(), 3);
+        assert!(
+            store
+                .channel("rust", now_millis() + 1000, 10)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(store.recent(10).unwrap().len(), 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retention_deletes_old_segments() {
+        let dir = temp_dir("store-retention");
+        let retention = Retention {
+            max_age: None,
+            max_segments: Some(2),
+        };
+        let mut store = MessageStore::open(&dir, retention.clone()).unwrap();
+        // One message per segment.
+        store.segment_len = 1;
+        for message in messages("general", 5) {
+            store.append(&message).unwrap();
+        }
+
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        let store = MessageStore::open(&dir, retention).unwrap();
+        assert_eq!(
+            texts(&store.recent(10).unwrap()),
+            ["message 4", "message 5"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_from_corrupted_tail() {
+        let dir = temp_dir("store-corruption");
+        let mut store = MessageStore::open(&dir, Retention::default()).unwrap();
+        for message in messages("general", 3) {
+            store.append(&message).unwrap();
+        }
+        drop(store);
+
+        // Flip a byte in the last record, and leave half a record after it.
+        let path = segment_path(&dir, 0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        bytes.extend_from_slice(&[0, 0, 0, 42, 1, 2]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut store = MessageStore::open(&dir, Retention::default()).unwrap();
+        assert_eq!(
+            texts(&store.recent(10).unwrap()),
+            ["message 1", "message 2"]
+        );
+        store.append(&messages("general", 1)[0]).unwrap();
+        let store = MessageStore::open(&dir, Retention::default()).unwrap();
+        assert_eq!(
+            texts(&store.recent(10).unwrap()),
+            ["message 1", "message 2", "message 1"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_corrupted_sealed_segments_alone() {
+        let dir = temp_dir("store-sealed-corruption");
+        let mut store = MessageStore::open(&dir, Retention::default()).unwrap();
+        let messages = messages("general", 4);
+        for message in &messages[..3] {
+            store.append(message).unwrap();
+        }
+        // The last message starts a segment of its own.
+        store.segment_len = 1;
+        store.append(&messages[3]).unwrap();
+        drop(store);
+
+        let path = segment_path(&dir, 0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let store = MessageStore::open(&dir, Retention::default()).unwrap();
+        assert_eq!(
+            texts(&store.recent(10).unwrap()),
+            ["message 1", "message 2", "message 4"]
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
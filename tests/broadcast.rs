@@ -0,0 +1,34 @@
+//! Nodes finding each other through UDP broadcast, on loopback.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use chat_async::{
+    connect::multicast::communicator::BroadcastCommunicator,
+    node::{Node, NodeConfig},
+};
+use tokio::time::timeout;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finds_nodes_through_broadcast() {
+    let mut nodes = Vec::new();
+    for name in ["a", "b", "c"] {
+        let mut config = NodeConfig::new(name, Ipv4Addr::LOCALHOST);
+        // The subnet-directed broadcast address of loopback.
+        config.multicast = SocketAddrV4::new(Ipv4Addr::new(127, 255, 255, 255), 24983);
+        let (node, events) = <Node<BroadcastCommunicator>>::start(config).await.unwrap();
+        nodes.push((node, events));
+    }
+
+    timeout(TIMEOUT, async {
+        while nodes.iter().any(|(node, _)| node.peers().len() < 2) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("never found each other");
+}
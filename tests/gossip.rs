@@ -0,0 +1,493 @@
+//! Links [`ConnectionManager`]s by hand into meshes discovery wouldn't build, to see messages relayed through them
+//! and delivered in causal order, links coming back after they break, peers that stopped reading left behind, and
+//! links refused or dropped.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use chat_async::{
+    chat::{ChatMessage, DEFAULT_CHANNEL, Frame, MessageId, PrivateMessage},
+    connect::{
+        admission::AdmissionPolicy,
+        causal::VectorClock,
+        gossip::DEFAULT_TTL,
+        manager::{
+            ConnectionManager, Event, FORGET_AFTER, HANDSHAKE_TIMEOUT, LinkState,
+            MAX_DISCOVERED_PEERS,
+        },
+        mux::{QUEUE_LEN, QueuePolicy},
+    },
+    handle_incoming_connections,
+    identity::{Identity, KeyStore, PublicKey},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Notify,
+        mpsc::{self, UnboundedReceiver},
+    },
+    time::timeout,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn start_manager(name: &str) -> (ConnectionManager, UnboundedReceiver<Event>) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let me = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    let (manager, events) = ConnectionManager::new(
+        me,
+        name.to_owned(),
+        Identity::generate(),
+        KeyStore::default(),
+        None,
+    );
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(handle_incoming_connections(tx, listener));
+    tokio::spawn(manager.clone().manage(rx));
+
+    (manager, events)
+}
+
+async fn link(a: &ConnectionManager, b: &ConnectionManager) {
+    a.connect(b.me());
+    wait_linked(a, b).await;
+}
+
+async fn wait_linked(a: &ConnectionManager, b: &ConnectionManager) {
+    timeout(TIMEOUT, async {
+        while !a.is_connected(b.me()) || !b.is_connected(a.me()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("never linked");
+}
+
+/// Wait for the next event `matches` picks something out of, skipping every other event.
+async fn next_event<T>(
+    events: &mut UnboundedReceiver<Event>,
+    mut matches: impl FnMut(Event) -> Option<T>,
+) -> T {
+    timeout(TIMEOUT, async {
+        loop {
+            if let Some(matched) = matches(events.recv().await.unwrap()) {
+                return matched;
+            }
+        }
+    })
+    .await
+    .expect("no matching event received")
+}
+
+async fn next_message(events: &mut UnboundedReceiver<Event>) -> ChatMessage {
+    timeout(TIMEOUT, async {
+        loop {
+            if let Event::Message { message, .. } = events.recv().await.unwrap() {
+                return message;
+            }
+        }
+    })
+    .await
+    .expect("no message received")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relays_once_through_other_nodes() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, mut b_events) = start_manager("b").await;
+    let (c, mut c_events) = start_manager("c").await;
+    link(&a, &b).await;
+    link(&b, &c).await;
+
+    // c only hears from a through b.
+    let id = a.publish(DEFAULT_CHANNEL, "over the hill".to_owned()).await;
+    let message = next_message(&mut c_events).await;
+    assert_eq!((message.id, message.author.as_str()), (id, "a"));
+    assert_eq!(message.text, "over the hill");
+    assert_eq!(message.ttl, DEFAULT_TTL - 2);
+    assert_eq!(next_message(&mut b_events).await.id, id);
+
+    // Now that c hears from a both directly and through b, it still only gets the message once.
+    link(&a, &c).await;
+    let id = a.publish(DEFAULT_CHANNEL, "twice?".to_owned()).await;
+    assert_eq!(next_message(&mut c_events).await.id, id);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    while let Ok(event) = c_events.try_recv() {
+        assert!(!matches!(event, Event::Message { .. }), "{event:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_messages_sent_out_of_order_in_causal_order() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, mut b_events) = start_manager("b").await;
+    link(&a, &b).await;
+
+    // carol writes three messages, each after the one before, and dave replies to the last of them.
+    let (carol, dave) = (
+        Identity::generate().public_key(),
+        Identity::generate().public_key(),
+    );
+    let message = |origin, seq, deps: &[(PublicKey, u64)], text: &str| ChatMessage {
+        id: MessageId { origin, seq },
+        ttl: DEFAULT_TTL,
+        deps: VectorClock(deps.iter().copied().collect()),
+        channel: DEFAULT_CHANNEL.to_owned(),
+        author: "carol".to_owned(),
+        text: text.to_owned(),
+    };
+    let written = [
+        message(carol, 1, &[], "first"),
+        message(carol, 2, &[(carol, 1)], "second"),
+        message(carol, 3, &[(carol, 2)], "third"),
+        message(dave, 1, &[(carol, 3)], "reply"),
+    ];
+    for message in written.iter().rev() {
+        a.broadcast(Frame::Chat(message.clone())).await;
+    }
+
+    let mut delivered = Vec::new();
+    while delivered.len() < written.len() {
+        delivered.push(next_message(&mut b_events).await.text);
+    }
+    assert_eq!(delivered, ["first", "second", "third", "reply"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relays_through_nodes_not_in_the_channel() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, mut b_events) = start_manager("b").await;
+    let (c, mut c_events) = start_manager("c").await;
+    a.join("rust").await.unwrap();
+    c.join("rust").await.unwrap();
+    link(&a, &b).await;
+    link(&b, &c).await;
+
+    let id = a.publish("rust", "anyone?".to_owned()).await;
+    let message = next_message(&mut c_events).await;
+    assert_eq!((message.id, message.channel.as_str()), (id, "rust"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    while let Ok(event) = b_events.try_recv() {
+        assert!(!matches!(event, Event::Message { .. }), "{event:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn private_messages_go_by_the_authenticated_name() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, mut b_events) = start_manager("b").await;
+    link(&a, &b).await;
+
+    a.broadcast(Frame::Private(PrivateMessage {
+        id: 1000,
+        author: "c".to_owned(),
+        text: "it's me, c".to_owned(),
+    }))
+    .await;
+    a.send_private("b", "it's me, a".to_owned()).await.unwrap();
+    let (from, name, message) = next_event(&mut b_events, |event| match event {
+        Event::PrivateMessage {
+            from,
+            name,
+            message,
+            ..
+        } => Some((from, name, message)),
+        _ => None,
+    })
+    .await;
+    assert_eq!((from, name.as_str()), (a.me(), "a"));
+    assert_eq!(message.text, "it's me, a");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dials_again_after_the_link_breaks() {
+    let (a, mut a_events) = start_manager("a").await;
+    let (b, _b_events) = start_manager("b").await;
+    link(&a, &b).await;
+
+    b.disconnect(a.me());
+    let mut states = Vec::new();
+    next_event(&mut a_events, |event| {
+        matches!(event, Event::PeerDisconnected { .. }).then_some(())
+    })
+    .await;
+    next_event(&mut a_events, |event| match event {
+        Event::Link { peer, state } if peer == b.me() => {
+            states.push(state);
+            (state == LinkState::Up).then_some(())
+        }
+        _ => None,
+    })
+    .await;
+    assert!(matches!(
+        states[..],
+        [
+            LinkState::BackingOff { attempt: 1, .. },
+            LinkState::Connecting,
+            LinkState::Up
+        ]
+    ));
+    wait_linked(&a, &b).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dials_the_announced_endpoints_in_turn() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, _b_events) = start_manager("b").await;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let gone = listener.local_addr().unwrap();
+    drop(listener);
+
+    a.connect_via(
+        b.me(),
+        None,
+        vec!["[::1]:1".parse().unwrap(), gone, b.me().into()],
+    );
+    wait_linked(&a, &b).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_the_announced_node_may_answer_at_its_endpoints() {
+    let (a, mut a_events) = start_manager("a").await;
+    let (b, _b_events) = start_manager("b").await;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let victim = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    drop(listener);
+
+    // b answers at the endpoints announced for someone else, or for itself but with another key.
+    let other = Identity::generate().public_key();
+    for (peer, key) in [(victim, None), (b.me(), Some(other))] {
+        a.connect_via(peer, key, vec![b.me().into()]);
+        next_event(&mut a_events, |event| match event {
+            Event::Link {
+                peer: backing_off,
+                state: LinkState::BackingOff { .. },
+            } if backing_off == peer => Some(()),
+            _ => None,
+        })
+        .await;
+        assert!(a.peers().is_empty());
+        a.disconnect(peer);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inbound_links_go_by_the_announced_id() {
+    let (b, _b_events) = start_manager("b").await;
+    // Known by an address other than the one it dials from, as behind a NAT.
+    let behind_nat = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 4983);
+    let (a, _a_events) = ConnectionManager::new(
+        behind_nat,
+        "a".to_owned(),
+        Identity::generate(),
+        KeyStore::default(),
+        None,
+    );
+
+    a.connect(b.me());
+    timeout(TIMEOUT, async {
+        while b.peers() != [behind_nat] || !a.is_connected(b.me()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("never linked");
+}
+
+#[tokio::test]
+async fn backs_off_further_while_the_peer_is_unreachable() {
+    let (a, mut a_events) = start_manager("a").await;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let gone = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    drop(listener);
+
+    a.connect(gone);
+    let mut delays = Vec::new();
+    while delays.len() < 3 {
+        let delay = next_event(&mut a_events, |event| match event {
+            Event::Link {
+                state: LinkState::BackingOff { attempt, delay },
+                ..
+            } => Some((attempt, delay)),
+            _ => None,
+        })
+        .await;
+        delays.push(delay);
+    }
+    assert_eq!(
+        delays
+            .iter()
+            .map(|(attempt, _)| *attempt)
+            .collect::<Vec<_>>(),
+        [1, 2, 3]
+    );
+    // With up to half of each taken off at random, the third delay is still longer than the first.
+    assert!(delays[2].1 > delays[0].1);
+
+    a.disconnect(gone);
+    assert!(!a.is_connected(gone));
+}
+
+#[tokio::test(start_paused = true)]
+async fn gives_up_on_discovered_peers_not_heard_of_again() {
+    let (a, mut a_events) = start_manager("a").await;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let gone = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    drop(listener);
+
+    let start = tokio::time::Instant::now();
+    a.connect_discovered(gone);
+    // Time only moves on when nothing else happens, so the backoff flies by.
+    let peer = timeout(FORGET_AFTER * 2, async {
+        loop {
+            if let Event::Link {
+                peer,
+                state: LinkState::GaveUp,
+            } = a_events.recv().await.unwrap()
+            {
+                return peer;
+            }
+        }
+    })
+    .await
+    .expect("never gave up");
+    assert_eq!(peer, gone);
+    assert!(start.elapsed() >= FORGET_AFTER);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dials_only_so_many_discovered_peers() {
+    let (a, mut a_events) = start_manager("a").await;
+    // Nothing listens on these, so each attempt is refused straight away.
+    let unreachable: Vec<_> = (0..=MAX_DISCOVERED_PEERS as u16)
+        .map(|i| SocketAddrV4::new(Ipv4Addr::new(127, 0, 1, 1), 1 + i))
+        .collect();
+    let (discovered, extra) = unreachable.split_at(MAX_DISCOVERED_PEERS);
+    for peer in discovered {
+        a.connect_discovered(*peer);
+    }
+    a.connect_via(extra[0], None, vec![]);
+
+    let mut backing_off = std::collections::HashSet::new();
+    while backing_off.len() < discovered.len() {
+        let peer = next_event(&mut a_events, |event| match event {
+            Event::Link { peer, state } => Some((peer, state)),
+            _ => None,
+        })
+        .await;
+        assert_ne!(peer.0, extra[0]);
+        if matches!(peer.1, LinkState::BackingOff { .. }) {
+            backing_off.insert(peer.0);
+        }
+    }
+}
+
+/// Forward the first connection made to the returned address to `to`, until the returned [`Notify`] fires, after
+/// which nothing more is read from whoever connected, as if `to` had stopped reading.
+async fn stalling_proxy(to: SocketAddrV4) -> (SocketAddrV4, Arc<Notify>) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+    let stall = Arc::new(Notify::new());
+    let stalled = stall.clone();
+    tokio::spawn(async move {
+        let (client, _) = listener.accept().await.unwrap();
+        let (mut client_read, mut client_write) = client.into_split();
+        let (mut server_read, mut server_write) =
+            TcpStream::connect(to).await.unwrap().into_split();
+        tokio::spawn(async move { tokio::io::copy(&mut server_read, &mut client_write).await });
+        let mut buf = vec![0; 16 << 10];
+        loop {
+            tokio::select! {
+                read = client_read.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => server_write.write_all(&buf[..n]).await.unwrap(),
+                },
+                _ = stalled.notified() => break,
+            }
+        }
+        std::future::pending::<()>().await;
+        drop((client_read, server_write));
+    });
+
+    (addr, stall)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_sending_to_others_while_a_peer_stopped_reading() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, mut b_events) = start_manager("b").await;
+    let (c, _c_events) = start_manager("c").await;
+    a.set_queue_policy(QueuePolicy::DropOldest);
+    link(&a, &b).await;
+    let (proxy, stall) = stalling_proxy(c.me()).await;
+    a.connect(proxy);
+    // a knows c by the address it dialed.
+    timeout(TIMEOUT, async {
+        while !a.is_connected(proxy) || !c.is_connected(a.me()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("never linked");
+
+    stall.notify_one();
+    // Far more than a flow control window, so that the queue for c fills up.
+    let padding = "x".repeat(2 << 10);
+    for i in 0..600 {
+        let id = a.publish(DEFAULT_CHANNEL, format!("{i} {padding}")).await;
+        assert_eq!(next_message(&mut b_events).await.id, id);
+    }
+
+    let stats = a.queue_stats()[&proxy];
+    assert!(stats.dropped > 0, "{stats:?}");
+    assert!(
+        stats.queued <= QUEUE_LEN && stats.peak <= QUEUE_LEN,
+        "{stats:?}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_connections_over_the_limit() {
+    let (a, _a_events) = start_manager("a").await;
+    let (b, _b_events) = start_manager("b").await;
+    let (c, _c_events) = start_manager("c").await;
+    b.set_admission(AdmissionPolicy {
+        max_connections: 1,
+        ..Default::default()
+    });
+    link(&a, &b).await;
+
+    c.connect(b.me());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(b.peers(), [a.me()]);
+    assert!(!c.is_connected(b.me()));
+
+    // Once a leaves, there's room for c.
+    a.disconnect(b.me());
+    wait_linked(&c, &b).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stalled_connections_give_their_place_up() {
+    let (b, _b_events) = start_manager("b").await;
+    let (c, _c_events) = start_manager("c").await;
+    b.set_admission(AdmissionPolicy {
+        max_connections: 1,
+        ..Default::default()
+    });
+
+    // Takes the only place, but never starts the handshake.
+    let mut stalled = TcpStream::connect(b.me()).await.unwrap();
+    let mut buf = [0; 64];
+    timeout(HANDSHAKE_TIMEOUT + TIMEOUT, async {
+        while let Ok(1..) = stalled.read(&mut buf).await {}
+    })
+    .await
+    .expect("stalled connection was never dropped");
+
+    link(&c, &b).await;
+}
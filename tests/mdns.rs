@@ -0,0 +1,53 @@
+//! Nodes finding each other over mDNS, through real sockets on loopback.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use chat_async::{
+    connect::{discovery::Discovery, mdns::MDNS_ADDRESS},
+    node::{Node, NodeConfig},
+};
+use tokio::time::timeout;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration for a node speaking mDNS on `port` of the mDNS group, rather than on 5353, so that tests neither see
+/// each other's nodes nor the real mDNS traffic of the host.
+fn config(name: &str, port: u16) -> NodeConfig {
+    let mut config = NodeConfig::new(name, Ipv4Addr::LOCALHOST);
+    config.discovery = Discovery::Mdns;
+    config.mdns = SocketAddrV4::new(*MDNS_ADDRESS.ip(), port);
+    config
+}
+
+async fn wait_linked(a: &Node, b: &Node) {
+    timeout(TIMEOUT, async {
+        while !a.peers().contains(&b.id()) || !b.peers().contains(&a.id()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("never linked");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finds_and_forgets_nodes_over_mdns() {
+    let (a, _a_events) = Node::start(config("a", 25353)).await.unwrap();
+    let (b, _b_events) = Node::start(config("b", 25353)).await.unwrap();
+    let (c, _c_events) = Node::start(config("c", 25353)).await.unwrap();
+    wait_linked(&a, &b).await;
+    wait_linked(&b, &c).await;
+    wait_linked(&a, &c).await;
+
+    let id = c.id();
+    c.shutdown().await.unwrap();
+    timeout(TIMEOUT, async {
+        while a.peers().contains(&id) || b.peers().contains(&id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("never forgot the node that left");
+}
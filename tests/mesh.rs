@@ -0,0 +1,588 @@
+//! Starts several nodes in-process, finding each other through the in-memory communicator and talking over loopback.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use chat_async::{
+    chat::DEFAULT_CHANNEL,
+    connect::{
+        manager::{Event, JoinError, LinkState, MAX_CHANNEL_LEN, MAX_CHANNELS, SendError},
+        multicast::{
+            AsyncTryFromSocketAddr,
+            communicator::{Communicator, MemoryCommunicator},
+        },
+        transfer::{CHUNK_LEN, part_path},
+    },
+    identity::KeyStore,
+    node::{Node, NodeConfig},
+    room::RoomKey,
+};
+use tokio::{sync::mpsc::UnboundedReceiver, time::timeout};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestNode {
+    node: Node<MemoryCommunicator>,
+    events: UnboundedReceiver<Event>,
+}
+
+impl TestNode {
+    /// Wait for the next event `matches` picks something out of, skipping every other event.
+    async fn next_event<T>(&mut self, mut matches: impl FnMut(Event) -> Option<T>) -> T {
+        timeout(TIMEOUT, async {
+            loop {
+                if let Some(matched) = matches(self.events.recv().await.unwrap()) {
+                    return matched;
+                }
+            }
+        })
+        .await
+        .expect("no matching event received")
+    }
+
+    /// Wait for the next chat message, skipping every other event, and return its author and text.
+    async fn next_message(&mut self) -> (String, String) {
+        self.next_event(|event| match event {
+            Event::Message { message, .. } => Some((message.author, message.text)),
+            _ => None,
+        })
+        .await
+    }
+}
+
+/// Configuration for a node on its own multicast `group`, so that tests don't see each other's nodes.
+fn config(name: &str, group: u16) -> NodeConfig {
+    let mut config = NodeConfig::new(name, Ipv4Addr::LOCALHOST);
+    config.multicast = SocketAddrV4::new(Ipv4Addr::new(224, 0, 1, 1), group);
+    config
+}
+
+async fn start_node(config: NodeConfig) -> TestNode {
+    let (node, events) = Node::start(config).await.unwrap();
+    TestNode { node, events }
+}
+
+async fn start_nodes(n: usize, group: u16) -> Vec<TestNode> {
+    let mut nodes = Vec::new();
+    for i in 0..n {
+        nodes.push(start_node(config(&format!("node{i}"), group)).await);
+    }
+
+    nodes
+}
+
+/// Poll `condition` until it holds, panicking after [`TIMEOUT`].
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    timeout(TIMEOUT, async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition never held")
+}
+
+async fn wait_for_full_mesh(nodes: &[TestNode]) {
+    wait_until(|| {
+        nodes.iter().all(|a| {
+            nodes
+                .iter()
+                .all(|b| a.node.id() == b.node.id() || a.node.peers().contains(&b.node.id()))
+        })
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_mesh_discovery() {
+    let nodes = start_nodes(4, 1).await;
+
+    wait_for_full_mesh(&nodes).await;
+    for node in &nodes {
+        assert_eq!(node.node.peers().len(), 3);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_to_every_node() {
+    let mut nodes = start_nodes(4, 2).await;
+    wait_for_full_mesh(&nodes).await;
+
+    let sender = "node0".to_owned();
+    nodes[0].node.send(DEFAULT_CHANNEL, "hello everyone").await;
+
+    for node in &mut nodes[1..] {
+        assert_eq!(
+            node.next_message().await,
+            (sender.clone(), "hello everyone".to_owned())
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn removes_peer_on_shutdown() {
+    let mut nodes = start_nodes(3, 3).await;
+    wait_for_full_mesh(&nodes).await;
+
+    let leaving = nodes.pop().unwrap();
+    let id = leaving.node.id();
+    leaving.node.shutdown().await.unwrap();
+
+    wait_until(|| nodes.iter().all(|node| !node.node.peers().contains(&id))).await;
+    for node in &nodes {
+        assert_eq!(node.node.peers().len(), 1);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn private_rooms_are_isolated() {
+    let in_room = |name: &str| {
+        let mut config = config(name, 4);
+        config.room = Some(RoomKey::from_passphrase("fourth floor"));
+        config
+    };
+    let outsider = start_node(config("outsider", 4)).await;
+    let nodes = [
+        start_node(in_room("alice")).await,
+        start_node(in_room("bob")).await,
+    ];
+
+    wait_for_full_mesh(&nodes).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(outsider.node.peers().is_empty());
+    for node in &nodes {
+        assert_eq!(node.node.peers().len(), 1);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_only_to_channel_members() {
+    let mut nodes = start_nodes(3, 5).await;
+    wait_for_full_mesh(&nodes).await;
+
+    for node in &nodes[..2] {
+        assert_eq!(node.node.join_channel("rust").await, Ok(true));
+    }
+    assert!(nodes[2].node.part_channel(DEFAULT_CHANNEL).await);
+    let mut members = vec![nodes[0].node.id(), nodes[1].node.id()];
+    members.sort();
+    let outsider = nodes[2].node.id();
+    wait_until(|| {
+        nodes.iter().all(|node| {
+            let channels = node.node.network_channels();
+            channels.get("rust") == Some(&members)
+                && channels.get(DEFAULT_CHANNEL) == Some(&members)
+                && channels
+                    .values()
+                    .all(|members| !members.contains(&outsider))
+        })
+    })
+    .await;
+
+    let sender = "node0".to_owned();
+    nodes[0].node.send("rust", "hello rustaceans").await;
+    assert_eq!(
+        nodes[1].next_message().await,
+        (sender.clone(), "hello rustaceans".to_owned())
+    );
+    nodes[0].node.send(DEFAULT_CHANNEL, "hello general").await;
+    assert_eq!(
+        nodes[1].next_message().await,
+        (sender.clone(), "hello general".to_owned())
+    );
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    while let Ok(event) = nodes[2].events.try_recv() {
+        assert!(!matches!(event, Event::Message { .. }), "{event:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn private_messages_reach_only_their_recipient() {
+    let mut nodes = start_nodes(3, 6).await;
+    wait_for_full_mesh(&nodes).await;
+
+    let (sender, recipient) = (nodes[0].node.id(), nodes[1].node.id());
+    let sent = nodes[0]
+        .node
+        .send_private("node1", "just between us")
+        .await
+        .unwrap();
+    assert!(!sent.queued);
+
+    let message = nodes[1]
+        .next_event(|event| match event {
+            Event::PrivateMessage {
+                from,
+                name,
+                message,
+                ..
+            } => Some((from, name, message)),
+            _ => None,
+        })
+        .await;
+    assert_eq!(message.0, sender);
+    assert_eq!(message.1, "node0");
+    assert_eq!(message.2.text, "just between us");
+    let delivered = nodes[0]
+        .next_event(|event| match event {
+            Event::Delivered { peer, id } => Some((peer, id)),
+            _ => None,
+        })
+        .await;
+    assert_eq!(delivered, (recipient, sent.id));
+
+    assert_eq!(
+        nodes[0].node.send_private("nobody", "hello?").await,
+        Err(SendError::Unknown("nobody".to_owned()))
+    );
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    while let Ok(event) = nodes[2].events.try_recv() {
+        assert!(!matches!(event, Event::PrivateMessage { .. }), "{event:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn late_joiners_catch_up_on_history() {
+    let mut nodes = start_nodes(2, 7).await;
+    wait_for_full_mesh(&nodes).await;
+    let texts = ["first", "second", "third"];
+    for text in texts {
+        nodes[0].node.send(DEFAULT_CHANNEL, text).await;
+        assert_eq!(nodes[1].next_message().await.1, text);
+    }
+
+    let mut late = start_node(config("late", 7)).await;
+    for text in texts {
+        assert_eq!(
+            late.next_message().await,
+            ("node0".to_owned(), text.to_owned())
+        );
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    while let Ok(event) = late.events.try_recv() {
+        assert!(!matches!(event, Event::Message { .. }), "{event:?}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_messages_across_restarts() {
+    let dir = std::env::temp_dir().join(format!("chat-async-mesh-store-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut stored = config("stored", 8);
+    stored.store = Some(dir.clone());
+    let mut nodes = [
+        start_node(stored.clone()).await,
+        start_node(config("other", 8)).await,
+    ];
+    wait_for_full_mesh(&nodes).await;
+
+    let sent = nodes[0].node.send(DEFAULT_CHANNEL, "mine").await;
+    nodes[1].node.send(DEFAULT_CHANNEL, "theirs").await;
+    assert_eq!(nodes[0].next_message().await.1, "theirs");
+    let [first, _other] = nodes;
+    first.node.shutdown().await.unwrap();
+
+    let restarted = start_node(stored).await;
+    let texts: Vec<_> = restarted
+        .node
+        .stored_messages(DEFAULT_CHANNEL, 10)
+        .unwrap()
+        .into_iter()
+        .map(|stored| stored.message.text)
+        .collect();
+    assert_eq!(texts, ["mine", "theirs"]);
+    assert!(restarted.node.send(DEFAULT_CHANNEL, "again").await.seq > sent.seq);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queues_private_messages_until_the_recipient_is_back() {
+    let path = std::env::temp_dir().join(format!("chat-async-mesh-outbox-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut sender = config("sender", 9);
+    sender.outbox = Some(path.clone());
+    let recipient = config("recipient", 9);
+    let nodes = [
+        start_node(sender.clone()).await,
+        start_node(recipient.clone()).await,
+    ];
+    wait_for_full_mesh(&nodes).await;
+    let [sending, receiving] = nodes;
+    receiving.node.shutdown().await.unwrap();
+    wait_until(|| sending.node.peers().is_empty()).await;
+
+    let sent = sending
+        .node
+        .send_private("recipient", "while you were out")
+        .await
+        .unwrap();
+    assert!(sent.queued);
+    // The outbox survives the sender restarting too.
+    drop(sending);
+    let mut sending = start_node(sender).await;
+
+    let mut receiving = start_node(recipient).await;
+    let message = receiving
+        .next_event(|event| match event {
+            Event::PrivateMessage { message, .. } => Some(message),
+            _ => None,
+        })
+        .await;
+    assert_eq!(message.text, "while you were out");
+    let delivered = sending
+        .next_event(|event| match event {
+            Event::Delivered { id, .. } => Some(id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(delivered, sent.id);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_files_resuming_partial_transfers() {
+    let dir = std::env::temp_dir().join(format!("chat-async-mesh-files-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("screenshot.png");
+    let contents: Vec<u8> = (0..CHUNK_LEN * 3 + 7).map(|i| (i * 7) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let mut nodes = start_nodes(2, 10).await;
+    wait_for_full_mesh(&nodes).await;
+    let offer = nodes[0].node.offer_file("node1", &source).await.unwrap();
+    let (from, offered) = nodes[1]
+        .next_event(|event| match event {
+            Event::FileOffered { from, offer } => Some((from, offer)),
+            _ => None,
+        })
+        .await;
+    assert_eq!(offered, offer);
+
+    // An earlier attempt got the first chunk across.
+    let dest = dir.join("received.png");
+    std::fs::write(part_path(&dest, &offer), &contents[..CHUNK_LEN]).unwrap();
+    let offset = nodes[1]
+        .node
+        .accept_file(from, offer.id, dest.clone())
+        .await
+        .unwrap();
+    assert_eq!(offset, CHUNK_LEN as u64);
+
+    for node in &mut nodes {
+        let finished = node
+            .next_event(|event| match event {
+                Event::TransferFinished { id, verified, .. } => Some((id, verified)),
+                _ => None,
+            })
+            .await;
+        assert_eq!(finished, (offer.id, true));
+    }
+    assert_eq!(std::fs::read(&dest).unwrap(), contents);
+
+    // An empty file has no chunk to send but still finishes.
+    let empty = dir.join("empty.txt");
+    std::fs::write(&empty, b"").unwrap();
+    let offer = nodes[0].node.offer_file("node1", &empty).await.unwrap();
+    nodes[1]
+        .next_event(|event| matches!(event, Event::FileOffered { .. }).then_some(()))
+        .await;
+    let dest = dir.join("received.txt");
+    nodes[1]
+        .node
+        .accept_file(from, offer.id, dest.clone())
+        .await
+        .unwrap();
+    for node in &mut nodes {
+        let finished = node
+            .next_event(|event| match event {
+                Event::TransferFinished { id, verified, .. } => Some((id, verified)),
+                _ => None,
+            })
+            .await;
+        assert_eq!(finished, (offer.id, true));
+    }
+    assert_eq!(std::fs::read(&dest).unwrap(), b"");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn chat_is_not_held_up_by_file_transfers() {
+    let dir = std::env::temp_dir().join(format!("chat-async-mesh-mux-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("video.mkv");
+    std::fs::write(&source, vec![42; 32 << 20]).unwrap();
+
+    let mut nodes = start_nodes(2, 11).await;
+    wait_for_full_mesh(&nodes).await;
+    let offer = nodes[0].node.offer_file("node1", &source).await.unwrap();
+    let from = nodes[1]
+        .next_event(|event| match event {
+            Event::FileOffered { from, .. } => Some(from),
+            _ => None,
+        })
+        .await;
+    nodes[1]
+        .node
+        .accept_file(from, offer.id, dir.join("received.mkv"))
+        .await
+        .unwrap();
+    nodes[1]
+        .next_event(|event| matches!(event, Event::TransferProgress { .. }).then_some(()))
+        .await;
+
+    nodes[0].node.send(DEFAULT_CHANNEL, "still there?").await;
+    let first = nodes[1]
+        .next_event(|event| match event {
+            Event::Message { message, .. } => Some(Some(message.text)),
+            Event::TransferFinished { .. } => Some(None),
+            _ => None,
+        })
+        .await;
+    assert_eq!(first.as_deref(), Some("still there?"));
+
+    // Not to remove the file while it's still being written, which takes longer than anything else waited for.
+    timeout(TIMEOUT * 12, async {
+        while !matches!(
+            nodes[1].events.recv().await.unwrap(),
+            Event::TransferFinished { id, .. } if id == offer.id
+        ) {}
+    })
+    .await
+    .expect("transfer never finished");
+    for node in nodes {
+        node.node.shutdown().await.unwrap();
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn dials_nodes_announced_with_a_legacy_hi() {
+    // The other node is on another group, so it's only heard of through the HI.
+    let other = start_node(config("other", 13)).await;
+    let mut legacy = config("legacy", 12);
+    legacy.legacy_hi = true;
+    let node = start_node(legacy).await;
+
+    let old_node = MemoryCommunicator::try_from_socket_addr(SocketAddrV4::new(
+        Ipv4Addr::new(224, 0, 1, 1),
+        12,
+    ))
+    .await
+    .unwrap();
+    let mut hi = b"HI".to_vec();
+    hi.extend_from_slice(&other.node.id().port().to_be_bytes());
+    old_node.communicate(&hi).await.unwrap();
+
+    wait_until(|| node.node.peers() == [other.node.id()]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn links_to_static_peers() {
+    // On different groups, as if across a VPN, so they never discover each other.
+    let remote = start_node(config("remote", 14)).await;
+    let mut local = config("local", 15);
+    local.peers = vec![remote.node.id().to_string()];
+    let local = start_node(local).await;
+
+    wait_until(|| local.node.peers() == [remote.node.id()]).await;
+    wait_until(|| remote.node.peers() == [local.node.id()]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn seeds_introduce_nodes_of_other_sites() {
+    let mut seed = config("seed", 16);
+    seed.seed = true;
+    let seed = start_node(seed).await;
+    let here = start_node(config("here", 16)).await;
+    wait_until(|| seed.node.peers() == [here.node.id()]).await;
+
+    // Only the seed is known at the other site.
+    let mut there = config("there", 17);
+    there.peers = vec![seed.node.id().to_string()];
+    let there = start_node(there).await;
+
+    wait_until(|| here.node.peers().contains(&there.node.id())).await;
+    wait_until(|| there.node.peers().contains(&here.node.id())).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_configured_seeds_are_listened_to() {
+    let mut seed = config("seed", 21);
+    seed.seed = true;
+    let seed = start_node(seed).await;
+    let mut far = config("far", 22);
+    far.peers = vec![seed.node.id().to_string()];
+    let far = start_node(far).await;
+    wait_until(|| seed.node.peers() == [far.node.id()]).await;
+
+    // Discovered rather than configured, so what the seed shares is ignored.
+    let near = start_node(config("near", 21)).await;
+    wait_until(|| near.node.peers() == [seed.node.id()]).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(near.node.peers(), [seed.node.id()]);
+    assert_eq!(far.node.peers(), [seed.node.id()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn outsiders_cant_claim_names_of_a_room() {
+    let room = RoomKey::from_passphrase("fourth floor");
+    let keys = KeyStore::default();
+    let mut alice = config("alice", 18);
+    alice.room = Some(room.clone());
+    alice.keys = keys.clone();
+    let alice = start_node(alice).await;
+
+    // Not in the room, and not discoverable either, so it dials in.
+    let mut impostor = config("bob", 19);
+    impostor.peers = vec![alice.node.id().to_string()];
+    let mut impostor = start_node(impostor).await;
+    impostor
+        .next_event(|event| {
+            matches!(
+                event,
+                Event::Link {
+                    state: LinkState::BackingOff { .. },
+                    ..
+                }
+            )
+            .then_some(())
+        })
+        .await;
+    assert_eq!(keys.get("bob"), None);
+
+    let mut bob = config("bob", 18);
+    bob.room = Some(room);
+    let bob = start_node(bob).await;
+    wait_until(|| alice.node.peers() == [bob.node.id()]).await;
+    assert!(keys.get("bob").is_some());
+}
+
+#[tokio::test]
+async fn channels_are_capped() {
+    let node = start_node(config("node0", 20)).await;
+
+    let long = "x".repeat(MAX_CHANNEL_LEN + 1);
+    assert_eq!(node.node.join_channel(&long).await, Err(JoinError::TooLong));
+    // Already in the default channel.
+    for i in 1..MAX_CHANNELS {
+        assert_eq!(node.node.join_channel(&format!("c{i}")).await, Ok(true));
+    }
+    assert_eq!(node.node.join_channel("c1").await, Ok(false));
+    assert_eq!(
+        node.node.join_channel("one-too-many").await,
+        Err(JoinError::TooMany)
+    );
+    assert_eq!(node.node.channels().len(), MAX_CHANNELS);
+}
+
+#[tokio::test]
+async fn only_ipv4_endpoints_are_advertised() {
+    let mut ipv6 = config("node0", 23);
+    ipv6.advertise = vec!["[2001:db8::1]:4000".parse().unwrap()];
+    assert!(Node::<MemoryCommunicator>::start(ipv6).await.is_err());
+}
@@ -0,0 +1,131 @@
+//! Pins the multicast wire format to golden datagrams, so that changing it by accident shows up here rather than as
+//! nodes of different versions not finding each other.
+//!
+//! After a deliberate change (along with a new [`VERSION`]), run with `UPDATE_GOLDEN=1` to write the new datagrams.
+
+use std::path::PathBuf;
+
+use chat_async::{
+    connect::multicast::{
+        message::{Header, MAGIC, MulticastMessage, VERSION},
+        server::{Decoded, MulticastServer, Security},
+    },
+    room::RoomKey,
+};
+
+type Server = MulticastServer<MulticastMessage>;
+
+const SENDER: u64 = 0x0123_4567_89ab_cdef;
+
+/// Compare `bytes` with the fixture called `name`, or write them to it with `UPDATE_GOLDEN` set.
+fn golden(name: &str, bytes: &[u8]) -> Vec<u8> {
+    let path =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("tests/fixtures/wire/{name}.bin"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, bytes).unwrap();
+    }
+    let fixture = std::fs::read(&path).unwrap();
+    assert_eq!(bytes, fixture, "{name} changed");
+
+    fixture
+}
+
+fn in_room() -> Security {
+    Security {
+        room: Some(RoomKey::from_passphrase("fourth floor")),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn announcements_keep_their_encoding() {
+    let mut buf = [0; 4096];
+    let message = MulticastMessage::NewServer { port: 4321 };
+    let encoded = Server::encode(&mut buf, message.clone(), SENDER, &Security::default()).unwrap();
+    let fixture = golden("new_server", encoded);
+
+    assert!(fixture.starts_with(&MAGIC));
+    assert_eq!(fixture[MAGIC.len()], VERSION);
+    assert_eq!(
+        Server::decode(&fixture, &Security::default()).unwrap(),
+        Some(Decoded {
+            message,
+            sender: SENDER,
+            signer: None,
+        })
+    );
+}
+
+#[test]
+fn room_announcements_keep_their_encoding() {
+    let mut buf = [0; 4096];
+    let message = MulticastMessage::Channels {
+        port: 4321,
+        channels: vec!["general".to_owned(), "rust".to_owned()],
+    };
+    let encoded = Server::encode(&mut buf, message.clone(), SENDER, &in_room()).unwrap();
+    let fixture = golden("channels_in_room", encoded);
+
+    let decoded = Server::decode(&fixture, &in_room()).unwrap().unwrap();
+    assert_eq!(decoded.message, message);
+}
+
+#[test]
+fn endpoint_announcements_keep_their_encoding() {
+    let mut buf = [0; 4096];
+    let message = MulticastMessage::Announce {
+        id: "192.168.1.20:4321".parse().unwrap(),
+        name: "alice".to_owned(),
+        endpoints: vec![
+            "203.0.113.7:4000".parse().unwrap(),
+            "[2001:db8::7]:4000".parse().unwrap(),
+            "192.168.1.20:4321".parse().unwrap(),
+        ],
+        features: vec!["mux".to_owned(), "files".to_owned()],
+    };
+    let encoded = Server::encode(&mut buf, message.clone(), SENDER, &Security::default()).unwrap();
+    let fixture = golden("announce", encoded);
+
+    let decoded = Server::decode(&fixture, &Security::default())
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded.message, message);
+}
+
+#[test]
+fn future_kinds_are_skipped() {
+    // A message added by a later version of the protocol, which this one can't decode.
+    let header = Header {
+        topic: "chat-async".to_owned(),
+        kind: 42,
+        sender: SENDER,
+        signature: None,
+        room_mac: None,
+    };
+    let mut datagram = MAGIC.to_vec();
+    datagram.push(VERSION);
+    datagram.extend(bincode::encode_to_vec(header, bincode::config::standard()).unwrap());
+    datagram.extend([42, 1, 2, 3]);
+    let fixture = golden("future_kind", &datagram);
+
+    assert_eq!(
+        Server::decode(&fixture, &Security::default()).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn other_traffic_and_versions_are_ignored() {
+    let ssdp = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\r\n";
+    assert_eq!(Server::decode(ssdp, &Security::default()).unwrap(), None);
+
+    let mut next_version = std::fs::read(
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wire/new_server.bin"),
+    )
+    .unwrap();
+    next_version[MAGIC.len()] = VERSION + 1;
+    assert_eq!(
+        Server::decode(&next_version, &Security::default()).unwrap(),
+        None
+    );
+}